@@ -0,0 +1,329 @@
+use anyhow::{anyhow, bail, Error, Result};
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+// Digit alphabet of a balanced base. The base is 2 * MAX + 1,
+// digits range from -MAX to MAX.
+pub trait Digits: Copy + Eq + std::hash::Hash + fmt::Debug {
+    const MAX: i8;
+
+    fn symbol(d: i8) -> char;
+    fn value(c: char) -> Option<i8>;
+
+    fn base() -> i64 {
+        2 * Self::MAX as i64 + 1
+    }
+}
+
+// Balanced base five as used by the SNAFU format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Quinary;
+
+impl Digits for Quinary {
+    const MAX: i8 = 2;
+
+    fn symbol(d: i8) -> char {
+        match d {
+            -2 => '=',
+            -1 => '-',
+            0 => '0',
+            1 => '1',
+            2 => '2',
+            _ => panic!("invalid quinary digit {}", d),
+        }
+    }
+
+    fn value(c: char) -> Option<i8> {
+        match c {
+            '=' => Some(-2),
+            '-' => Some(-1),
+            '0' => Some(0),
+            '1' => Some(1),
+            '2' => Some(2),
+            _ => None,
+        }
+    }
+}
+
+pub type Snafu = Balanced<Quinary>;
+
+// Signed integer of arbitrary size stored as balanced digits,
+// least significant first. Zero has no digits, and the most
+// significant digit is never zero.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Balanced<D> {
+    digits: Vec<i8>,
+    d: PhantomData<D>,
+}
+
+impl<D: Digits> Balanced<D> {
+    fn from_digits(mut digits: Vec<i8>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        Self {
+            digits,
+            d: PhantomData,
+        }
+    }
+
+    // Normalizes column sums into balanced digits.
+    fn from_columns(cols: impl IntoIterator<Item = i64>) -> Self {
+        let mut v = Vec::new();
+        let mut carry = 0;
+        for c in cols {
+            let (d, nc) = Self::split(c + carry);
+            v.push(d);
+            carry = nc;
+        }
+        while carry != 0 {
+            let (d, nc) = Self::split(carry);
+            v.push(d);
+            carry = nc;
+        }
+        Self::from_digits(v)
+    }
+
+    // Splits n into a balanced digit and the carry to the next position.
+    fn split(n: i64) -> (i8, i64) {
+        let b = D::base();
+        let mut r = n.rem_euclid(b);
+        if r > D::MAX as i64 {
+            r -= b;
+        }
+        (r as i8, (n - r) / b)
+    }
+
+    fn signum(&self) -> Ordering {
+        self.digits.last().map_or(Ordering::Equal, |d| d.cmp(&0))
+    }
+}
+
+impl<D: Digits> From<i64> for Balanced<D> {
+    fn from(n: i64) -> Self {
+        // split through i128 so that i64::MIN does not overflow
+        let b = D::base() as i128;
+        let mut n = n as i128;
+        let mut v = Vec::new();
+        while n != 0 {
+            let mut r = n.rem_euclid(b);
+            if r > D::MAX as i128 {
+                r -= b;
+            }
+            v.push(r as i8);
+            n = (n - r) / b;
+        }
+        Self::from_digits(v)
+    }
+}
+
+impl<D: Digits> TryFrom<&Balanced<D>> for i64 {
+    type Error = Error;
+
+    fn try_from(n: &Balanced<D>) -> Result<i64> {
+        // accumulate in i128 so that the partial sums near the limits
+        // do not overflow before the final value does
+        n.digits
+            .iter()
+            .rev()
+            .try_fold(0i128, |acc, &d| {
+                acc.checked_mul(D::base() as i128)?.checked_add(d as i128)
+            })
+            .and_then(|x| i64::try_from(x).ok())
+            .ok_or_else(|| anyhow!("{} does not fit into 64 bits", n))
+    }
+}
+
+impl<D: Digits> FromStr for Balanced<D> {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            bail!("empty number");
+        }
+        let mut v = Vec::with_capacity(s.len());
+        for (i, c) in s.chars().enumerate() {
+            match D::value(c) {
+                Some(d) => v.push(d),
+                None => bail!("invalid digit {:?} at column {} in {:?}", c, i + 1, s),
+            }
+        }
+        v.reverse();
+        Ok(Self::from_digits(v))
+    }
+}
+
+impl<D: Digits> fmt::Display for Balanced<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.digits.is_empty() {
+            return f.pad(&D::symbol(0).to_string());
+        }
+        let s: String = self.digits.iter().rev().map(|&d| D::symbol(d)).collect();
+        f.pad(&s)
+    }
+}
+
+impl<D: Digits> Ord for Balanced<D> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self - other).signum()
+    }
+}
+
+impl<D: Digits> PartialOrd for Balanced<D> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<D: Digits> Neg for &Balanced<D> {
+    type Output = Balanced<D>;
+
+    fn neg(self) -> Balanced<D> {
+        Balanced::from_digits(self.digits.iter().map(|d| -d).collect())
+    }
+}
+
+impl<D: Digits> Add for &Balanced<D> {
+    type Output = Balanced<D>;
+
+    fn add(self, other: Self) -> Balanced<D> {
+        let n = self.digits.len().max(other.digits.len());
+        let at = |v: &[i8], i| v.get(i).copied().unwrap_or(0) as i64;
+        Balanced::from_columns((0..n).map(|i| at(&self.digits, i) + at(&other.digits, i)))
+    }
+}
+
+impl<D: Digits> Sub for &Balanced<D> {
+    type Output = Balanced<D>;
+
+    fn sub(self, other: Self) -> Balanced<D> {
+        self + &-other
+    }
+}
+
+impl<D: Digits> Mul for &Balanced<D> {
+    type Output = Balanced<D>;
+
+    fn mul(self, other: Self) -> Balanced<D> {
+        if self.digits.is_empty() || other.digits.is_empty() {
+            return Balanced::from_digits(Vec::new());
+        }
+        let mut cols = vec![0i64; self.digits.len() + other.digits.len()];
+        for (i, &a) in self.digits.iter().enumerate() {
+            for (j, &b) in other.digits.iter().enumerate() {
+                cols[i + j] += a as i64 * b as i64;
+            }
+        }
+        Balanced::from_columns(cols)
+    }
+}
+
+macro_rules! by_value {
+    ($tr:ident, $f:ident) => {
+        impl<D: Digits> $tr for Balanced<D> {
+            type Output = Balanced<D>;
+
+            fn $f(self, other: Self) -> Balanced<D> {
+                (&self).$f(&other)
+            }
+        }
+    };
+}
+
+by_value!(Add, add);
+by_value!(Sub, sub);
+by_value!(Mul, mul);
+
+impl<D: Digits> Neg for Balanced<D> {
+    type Output = Balanced<D>;
+
+    fn neg(self) -> Balanced<D> {
+        -&self
+    }
+}
+
+impl<D: Digits> std::iter::Sum for Balanced<D> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::from_digits(Vec::new()), |acc, x| acc + x)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Balanced ternary, written with a non-ASCII minus sign.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    struct Ternary;
+
+    impl Digits for Ternary {
+        const MAX: i8 = 1;
+
+        fn symbol(d: i8) -> char {
+            ['−', '0', '+'][(d + 1) as usize]
+        }
+
+        fn value(c: char) -> Option<i8> {
+            ['−', '0', '+']
+                .iter()
+                .position(|&x| x == c)
+                .map(|i| i as i8 - 1)
+        }
+    }
+
+    #[test]
+    fn snafu_roundtrip() {
+        for (n, s) in [
+            (0, "0"),
+            (1, "1"),
+            (3, "1="),
+            (-3, "-2"),
+            (2022, "1=11-2"),
+            (-2022, "-2--1="),
+            (314159265, "1121-1110-1=0"),
+        ] {
+            let v = Snafu::from(n);
+            assert_eq!(v.to_string(), s);
+            assert_eq!(i64::try_from(&s.parse::<Snafu>().unwrap()).unwrap(), n);
+        }
+        assert_eq!("001=".parse::<Snafu>().unwrap().to_string(), "1=");
+        assert_eq!(format!("[{:>3}]", Snafu::from(0)), "[  0]");
+        assert_eq!(format!("[{:<3}]", Snafu::from(3)), "[1= ]");
+    }
+
+    #[test]
+    fn snafu_invalid() {
+        assert!("".parse::<Snafu>().is_err());
+        assert!("12x".parse::<Snafu>().is_err());
+        assert!("1 2".parse::<Snafu>().is_err());
+        let e = "1é=".parse::<Snafu>().unwrap_err().to_string();
+        assert_eq!(e, "invalid digit 'é' at column 2 in \"1é=\"");
+        // columns count characters, not bytes
+        let e = "−+x".parse::<Balanced<Ternary>>().unwrap_err().to_string();
+        assert_eq!(e, "invalid digit 'x' at column 3 in \"−+x\"");
+        let big = &Snafu::from(i64::MAX) + &Snafu::from(1);
+        assert!(i64::try_from(&big).is_err());
+        assert_eq!(i64::try_from(&Snafu::from(i64::MIN)).unwrap(), i64::MIN);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let nums = [-1000, -37, -2, -1, 0, 1, 2, 12, 125, 4096];
+        for a in nums {
+            for b in nums {
+                let (x, y) = (Snafu::from(a), Snafu::from(b));
+                assert_eq!(&x + &y, Snafu::from(a + b));
+                assert_eq!(&x - &y, Snafu::from(a - b));
+                assert_eq!(&x * &y, Snafu::from(a * b));
+                assert_eq!(x.cmp(&y), a.cmp(&b));
+                let (x, y) = (Balanced::<Ternary>::from(a), Balanced::<Ternary>::from(b));
+                assert_eq!(x * y, Balanced::<Ternary>::from(a * b));
+            }
+            assert_eq!(-Snafu::from(a), Snafu::from(-a));
+        }
+        assert_eq!(Balanced::<Ternary>::from(-5).to_string(), "−++");
+    }
+}
@@ -1,32 +1,59 @@
-pub fn run(input: &str) -> anyhow::Result<String> {
-    let (stk, vrearr) = parse(input);
+use crate::param::Param;
+use anyhow::{anyhow, bail, Result};
 
-    let p1 = sim(move1, &stk, &vrearr);
-    let p2 = sim(move2, &stk, &vrearr);
+const CAPACITY: Param<usize> = Param::new("day05.capacity", 3);
+
+pub fn run(input: &str) -> Result<String> {
+    let (stk, vrearr) = parse(input)?;
+    let limited = Limited::new(CAPACITY.get()?).map_err(|e| anyhow!("day05.capacity: {}", e))?;
+
+    if crate::verbose() {
+        let cranes: [&dyn Crane; 3] = [&Mover9000, &Mover9001, &limited];
+        for crane in cranes {
+            let states = trace(crane, &stk, &vrearr)?;
+            let last = states.last().unwrap();
+            println!("{}: {}", crane.name(), tops(last));
+            print!("{}", render(last));
+        }
+    }
+
+    let p1 = sim(&Mover9000, &stk, &vrearr)?;
+    let p2 = sim(&Mover9001, &stk, &vrearr)?;
     Ok(format!("{} {}", p1, p2))
 }
 
 type Stacks = Vec<Vec<char>>;
 
-type Rearr = (usize, usize, usize);
+// Move n crates, with the line of the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rearr {
+    n: usize,
+    from: usize,
+    to: usize,
+    line: usize,
+}
 
-fn parse(input: &str) -> (Stacks, Vec<Rearr>) {
+fn parse(input: &str) -> Result<(Stacks, Vec<Rearr>)> {
     let mut stkv = Vec::new();
-    let mut pref = true;
+    let mut nstk = None;
     let mut moves = Vec::new();
-    for line in input.lines() {
-        if line.starts_with(" 1   2   3 ") {
-            pref = false;
-        } else if pref {
+    for (i, line) in input.lines().enumerate() {
+        if nstk.is_none() && is_label_line(line) {
+            nstk = Some(line.split_whitespace().count());
+        } else if nstk.is_none() {
             stkv.push(line);
-        } else if line.starts_with("move") {
-            if let Some(r) = parse_rearr(line) {
-                moves.push(r);
-            }
+        } else if !line.trim().is_empty() {
+            let r = parse_rearr(line, i + 1)
+                .ok_or_else(|| anyhow!("line {}: malformed instruction {:?}", i + 1, line))?;
+            moves.push(r);
         }
     }
+    if nstk.is_none() && !stkv.is_empty() {
+        bail!("no stack numbers below the drawing");
+    }
 
     let mut stk = Stacks::new();
+    stk.resize_with(nstk.unwrap_or(0), Vec::new);
     let mut add = |i: usize, c: char| {
         if stk.len() <= i {
             stk.resize_with(i + 1, Vec::new);
@@ -42,48 +69,261 @@ fn parse(input: &str) -> (Stacks, Vec<Rearr>) {
         })
     }
 
-    (stk, moves)
+    Ok((stk, moves))
+}
+
+// The line with the stack numbers below the drawing.
+fn is_label_line(line: &str) -> bool {
+    line.trim_start().starts_with('1') && line.chars().all(|c| c == ' ' || c.is_ascii_digit())
 }
 
-fn parse_rearr(line: &str) -> Option<Rearr> {
-    let mut it = line
-        .split_whitespace()
-        .enumerate()
-        .filter_map(|(i, p)| (i % 2 == 1).then_some(p.parse::<usize>().ok()?));
-    Some((it.next()?, it.next()?, it.next()?))
+// move «n» from «from» to «to»
+fn parse_rearr(line: &str, lineno: usize) -> Option<Rearr> {
+    let w: Vec<_> = line.split_whitespace().collect();
+    match w[..] {
+        ["move", n, "from", from, "to", to] => Some(Rearr {
+            n: n.parse().ok()?,
+            from: from.parse().ok()?,
+            to: to.parse().ok()?,
+            line: lineno,
+        }),
+        _ => None,
+    }
+}
+
+// Checks that the instruction can be carried out on the stacks.
+fn validate(stk: &Stacks, r: &Rearr) -> Result<()> {
+    for i in [r.from, r.to] {
+        if !(1..=stk.len()).contains(&i) {
+            bail!("line {}: no stack {}, there are {}", r.line, i, stk.len());
+        }
+    }
+    let have = stk[r.from - 1].len();
+    if have < r.n {
+        let crates = if have == 1 { "crate" } else { "crates" };
+        bail!(
+            "line {}: stack {} has {} {}, {} requested",
+            r.line,
+            r.from,
+            have,
+            crates,
+            r.n
+        );
+    }
+    Ok(())
 }
 
-fn sim<F>(mut f: F, stk: &Stacks, vrearr: &[Rearr]) -> String
-where
-    F: FnMut(&mut Stacks, Rearr),
-{
+fn sim(crane: &dyn Crane, stk: &Stacks, vrearr: &[Rearr]) -> Result<String> {
     let mut stk = stk.clone();
-    vrearr.iter().copied().for_each(|r| f(&mut stk, r));
+    for r in vrearr {
+        validate(&stk, r)?;
+        crane.apply(&mut stk, r);
+    }
+    Ok(tops(&stk))
+}
+
+// The stacks before the first and after each instruction.
+fn trace(crane: &dyn Crane, stk: &Stacks, vrearr: &[Rearr]) -> Result<Vec<Stacks>> {
+    let mut states = vec![stk.clone()];
+    for r in vrearr {
+        let mut next = states.last().unwrap().clone();
+        validate(&next, r)?;
+        crane.apply(&mut next, r);
+        states.push(next);
+    }
+    Ok(states)
+}
 
+fn tops(stk: &Stacks) -> String {
     stk.iter().filter_map(|x| x.last()).collect()
 }
 
-fn move1(stk: &mut Stacks, r: Rearr) {
-    let (n, from, to) = r;
-    for _ in 0..n {
-        if let Some(c) = stk[from - 1].pop() {
-            stk[to - 1].push(c);
+// Drawing of the stacks in the format of the puzzle input.
+fn render(stk: &Stacks) -> String {
+    let height = stk.iter().map(|s| s.len()).max().unwrap_or(0);
+    let mut lines: Vec<String> = (0..height)
+        .rev()
+        .map(|h| {
+            let cells: Vec<_> = stk
+                .iter()
+                .map(|s| s.get(h).map_or(String::from("   "), |c| format!("[{}]", c)))
+                .collect();
+            cells.join(" ")
+        })
+        .collect();
+    let labels: Vec<_> = (1..=stk.len()).map(|i| format!("{:^3}", i)).collect();
+    lines.push(labels.join(" "));
+    lines.iter().map(|l| format!("{}\n", l)).collect()
+}
+
+trait Crane {
+    fn name(&self) -> String;
+    // Carries out a validated instruction.
+    fn apply(&self, stk: &mut Stacks, r: &Rearr);
+}
+
+// Moves one crate at a time.
+struct Mover9000;
+
+// Moves all the crates at once.
+struct Mover9001;
+
+// Moves at most cap crates at once.
+struct Limited {
+    cap: usize,
+}
+
+impl Limited {
+    fn new(cap: usize) -> Result<Limited> {
+        if cap == 0 {
+            bail!("a crane must lift at least 1 crate");
+        }
+        Ok(Limited { cap })
+    }
+}
+
+impl Crane for Mover9000 {
+    fn name(&self) -> String {
+        String::from("CrateMover 9000")
+    }
+
+    fn apply(&self, stk: &mut Stacks, r: &Rearr) {
+        for _ in 0..r.n {
+            let c = stk[r.from - 1].pop().unwrap();
+            stk[r.to - 1].push(c);
+        }
+    }
+}
+
+impl Crane for Mover9001 {
+    fn name(&self) -> String {
+        String::from("CrateMover 9001")
+    }
+
+    fn apply(&self, stk: &mut Stacks, r: &Rearr) {
+        let l0 = stk[r.from - 1].len();
+        let lift = stk[r.from - 1].split_off(l0 - r.n);
+        stk[r.to - 1].extend(lift);
+    }
+}
+
+impl Crane for Limited {
+    fn name(&self) -> String {
+        format!("crane lifting up to {}", self.cap)
+    }
+
+    fn apply(&self, stk: &mut Stacks, r: &Rearr) {
+        let mut left = r.n;
+        while left > 0 {
+            let n = left.min(self.cap);
+            Mover9001.apply(stk, &Rearr { n, ..*r });
+            left -= n;
         }
     }
 }
 
-fn move2(stk: &mut Stacks, r: Rearr) {
-    let (n, from, to) = r;
-    if from == to {
-        return;
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE: &str = "    [D]    
+[N] [C]    
+[Z] [M] [P]
+ 1   2   3 
+
+move 1 from 2 to 1
+move 3 from 1 to 3
+move 2 from 2 to 1
+move 1 from 1 to 2
+";
+
+    #[test]
+    fn day05_works() {
+        let (stk, moves) = parse(SAMPLE).unwrap();
+        assert_eq!(sim(&Mover9000, &stk, &moves).unwrap(), "CMZ");
+        assert_eq!(sim(&Mover9001, &stk, &moves).unwrap(), "MCD");
+        let limited = |cap| Limited::new(cap).unwrap();
+        assert_eq!(sim(&limited(1), &stk, &moves).unwrap(), "CMZ");
+        assert_eq!(sim(&limited(3), &stk, &moves).unwrap(), "MCD");
+        assert_eq!(sim(&limited(2), &stk, &moves).unwrap(), "MCZ");
+        assert_eq!(
+            Limited::new(0).err().unwrap().to_string(),
+            "a crane must lift at least 1 crate"
+        );
     }
-    let l0 = stk[from - 1].len();
-    let si = if n < l0 { l0 - n } else { 0 };
-    let l1 = stk[to - 1].len();
-    stk[to - 1].reserve(l1 + n);
-    for i in si..l0 {
-        let c = stk[from - 1][i];
-        stk[to - 1].push(c);
+
+    #[test]
+    fn render_roundtrip() {
+        let (stk, moves) = parse(SAMPLE).unwrap();
+        assert_eq!(
+            render(&stk),
+            SAMPLE.split("\n\n").next().unwrap().to_string() + "\n"
+        );
+        let states = trace(&Mover9001, &stk, &moves).unwrap();
+        assert_eq!(states.len(), moves.len() + 1);
+        assert_eq!(states[1], [vec!['Z', 'N', 'D'], vec!['M', 'C'], vec!['P']]);
+        for s in &states {
+            assert_eq!(&parse(&render(s)).unwrap().0, s);
+        }
+        assert_eq!(
+            render(&states[2]),
+            "        [D]\n        [N]\n    [C] [Z]\n    [M] [P]\n 1   2   3 \n"
+        );
+
+        let wide: Stacks = (0..12)
+            .map(|i| vec![(b'A' + i) as char; i as usize % 3])
+            .collect();
+        assert_eq!(parse(&render(&wide)).unwrap().0, wide);
+    }
+
+    #[test]
+    fn invalid_instructions() {
+        let drawing = SAMPLE.split("\n\n").next().unwrap();
+        let check = |moves: &str| {
+            let input = format!("{}\n\n{}", drawing, moves);
+            parse(&input)
+                .and_then(|(stk, moves)| sim(&Mover9001, &stk, &moves))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            check("move 5 from 3 to 1\n"),
+            "line 6: stack 3 has 1 crate, 5 requested"
+        );
+        assert_eq!(
+            check("move 1 from 2 to 1\nmove 3 from 2 to 1\n"),
+            "line 7: stack 2 has 2 crates, 3 requested"
+        );
+        assert_eq!(
+            check("move 1 from 4 to 1\n"),
+            "line 6: no stack 4, there are 3"
+        );
+        assert_eq!(
+            check("move 1 from 1 to 0\n"),
+            "line 6: no stack 0, there are 3"
+        );
+        assert_eq!(
+            check("move 1 from 1\n"),
+            "line 6: malformed instruction \"move 1 from 1\""
+        );
+        assert_eq!(
+            check("move -1 from 1 to 2\n"),
+            "line 6: malformed instruction \"move -1 from 1 to 2\""
+        );
+        assert_eq!(
+            check("jump 1 from 1 to 2\n"),
+            "line 6: malformed instruction \"jump 1 from 1 to 2\""
+        );
+        assert_eq!(
+            parse("[A]\n").unwrap_err().to_string(),
+            "no stack numbers below the drawing"
+        );
+
+        let (stk, moves) = parse(&format!(
+            "{}\n\nmove 0 from 1 to 3\nmove 3 from 2 to 2\n",
+            drawing
+        ))
+        .unwrap();
+        assert_eq!(sim(&Mover9000, &stk, &moves).unwrap(), "NDP");
     }
-    stk[from - 1].truncate(si);
 }
@@ -1,135 +1,305 @@
+use crate::param::Param;
 use crate::Cli;
-use anyhow::{anyhow, bail};
-use std::collections::HashMap;
+use anyhow::{anyhow, bail, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CAP: Param<usize> = Param::new("day07.cap", 70000000);
+const NEED: Param<usize> = Param::new("day07.need", 30000000);
+const DU: Param<String> = Param::new("day07.du", String::new());
+const WALK: Param<String> = Param::new("day07.walk", String::new());
 
 pub fn run(input: &str) -> anyhow::Result<String> {
-    let t = tree(input).ok_or_else(|| anyhow!("invalid tree"))?;
-    let sizes = dir_sizes(&t);
-    if sizes.is_empty() {
-        bail!("sizes empty");
+    let verbose = Cli::global().verbose;
+    // a log generated from a local directory replaces the input
+    let walk = WALK.get()?;
+    let walked;
+    let input = if walk.is_empty() {
+        input
+    } else {
+        walked = check_walk(Path::new(&walk))?;
+        if verbose {
+            print!("{}", walked);
+        }
+        &walked
+    };
+
+    let fs = tree(input)?;
+    if verbose {
+        print!("{}", fs.listing());
+        for path in fs.unlisted() {
+            println!("directory {} was never listed", path);
+        }
+    }
+    let du = DU.get()?;
+    if !du.is_empty() {
+        let id = fs
+            .lookup(&du)
+            .ok_or_else(|| anyhow!("no such file or directory: {}", du))?;
+        for (path, size) in fs.du(id) {
+            println!("{}\t{}", size, path);
+        }
     }
 
-    let verbose = Cli::global().verbose;
+    let sizes: Vec<usize> = fs.du(ROOT).into_iter().map(|(_, size)| size).collect();
 
     const LIM: usize = 100000;
     let p1: usize = sizes.iter().filter(|&&x| x <= LIM).sum();
 
-    const CAP: usize = 70000000;
-    const NEED: usize = 30000000;
-    let free = CAP - *sizes.last().unwrap();
+    let (cap, need) = (CAP.get()?, NEED.get()?);
+    let free = cap
+        .checked_sub(fs.size(ROOT))
+        .ok_or_else(|| anyhow!("disk capacity {} exceeded", cap))?;
     if verbose {
-        println!("free space: {}/{}", free, CAP);
+        println!("free space: {}/{}", free, cap);
     }
-    let p2 = sizes.iter().filter(|&&x| free + x >= NEED).min().unwrap();
+    let p2 = sizes
+        .iter()
+        .filter(|&&x| free + x >= need)
+        .min()
+        .ok_or_else(|| anyhow!("can't free {}", need))?;
 
     Ok(format!("{} {}", p1, p2))
 }
 
-fn dir_sizes(tree: &Vec<Tree>) -> Vec<usize> {
-    let mut r = Vec::new();
-    collect_dir_sizes(&mut r, tree);
-    r
+const ROOT: usize = 0;
+
+// Filesystem seen by the terminal log. Nodes are only ever added, and
+// always after their parent, so children have larger ids.
+#[derive(Debug)]
+struct Fs {
+    nodes: Vec<Node>,
 }
 
-fn collect_dir_sizes(m: &mut Vec<usize>, tree: &Vec<Tree>) -> usize {
-    let mut total = 0;
-    for te in tree {
-        match te {
-            Tree::File(size) => {
-                total += size;
-            }
-            Tree::Dir(t) => {
-                total += collect_dir_sizes(m, t);
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: usize,
+    stat: Stat,
+    children: Vec<usize>,
+    listed: bool,
+}
+
+impl Fs {
+    fn new() -> Fs {
+        Fs {
+            nodes: vec![Node {
+                name: String::from("/"),
+                parent: ROOT,
+                stat: Stat::Dir,
+                children: Vec::new(),
+                listed: false,
+            }],
+        }
+    }
+
+    fn path(&self, id: usize) -> String {
+        let mut names = Vec::new();
+        let mut id = id;
+        while id != ROOT {
+            names.push(self.nodes[id].name.as_str());
+            id = self.nodes[id].parent;
+        }
+        names
+            .iter()
+            .rev()
+            .fold(String::from("/"), |p, name| path_append(&p, name))
+    }
+
+    fn child(&self, dir: usize, name: &str) -> Option<usize> {
+        self.nodes[dir]
+            .children
+            .iter()
+            .copied()
+            .find(|&c| self.nodes[c].name == name)
+    }
+
+    fn lookup(&self, path: &str) -> Option<usize> {
+        path.strip_prefix('/')?
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(ROOT, |dir, name| self.child(dir, name))
+    }
+
+    fn is_dir(&self, id: usize) -> bool {
+        matches!(self.nodes[id].stat, Stat::Dir)
+    }
+
+    // Records the listing of a directory. Listing it again is fine
+    // as long as the contents are the same.
+    fn list(&mut self, dir: usize, ents: Vec<DirEnt>) -> Result<()> {
+        let mut names: Vec<_> = ents.iter().map(|de| de.name.as_str()).collect();
+        names.sort();
+        if let Some(w) = names.windows(2).find(|w| w[0] == w[1]) {
+            bail!("{} lists {:?} twice", self.path(dir), w[0]);
+        }
+
+        if self.nodes[dir].listed {
+            let mut old: Vec<_> = self.nodes[dir]
+                .children
+                .iter()
+                .map(|&c| (self.nodes[c].name.as_str(), &self.nodes[c].stat))
+                .collect();
+            let mut new: Vec<_> = ents.iter().map(|de| (de.name.as_str(), &de.stat)).collect();
+            old.sort();
+            new.sort();
+            if old != new {
+                bail!("{} listed again with different contents", self.path(dir));
             }
+            return Ok(());
         }
+
+        for de in ents {
+            let id = self.nodes.len();
+            self.nodes.push(Node {
+                name: de.name,
+                parent: dir,
+                stat: de.stat,
+                children: Vec::new(),
+                listed: false,
+            });
+            self.nodes[dir].children.push(id);
+        }
+        self.nodes[dir].listed = true;
+        Ok(())
     }
-    m.push(total);
-    total
-}
 
-#[derive(Debug)]
-enum Tree {
-    File(usize),
-    Dir(Vec<Tree>),
+    fn size(&self, id: usize) -> usize {
+        self.collect_dir_sizes()[id]
+    }
+
+    // Total size below each node, indexed by id.
+    fn collect_dir_sizes(&self) -> Vec<usize> {
+        let mut sizes: Vec<usize> = self
+            .nodes
+            .iter()
+            .map(|n| match n.stat {
+                Stat::File(size) => size,
+                Stat::Dir => 0,
+            })
+            .collect();
+        for id in (1..self.nodes.len()).rev() {
+            sizes[self.nodes[id].parent] += sizes[id];
+        }
+        sizes
+    }
+
+    fn is_within(&self, id: usize, top: usize) -> bool {
+        let mut id = id;
+        while id != top && id != ROOT {
+            id = self.nodes[id].parent;
+        }
+        id == top
+    }
+
+    // Path and total size of every directory from top down, like du.
+    // Subdirectories come before their parents, so top comes last.
+    fn du(&self, top: usize) -> Vec<(String, usize)> {
+        let sizes = self.collect_dir_sizes();
+        (top..self.nodes.len())
+            .rev()
+            .filter(|&id| self.is_dir(id) && self.is_within(id, top))
+            .map(|id| (self.path(id), sizes[id]))
+            .collect()
+    }
+
+    // Directories that appear in a listing but were never listed
+    // themselves, so their contents are unknown.
+    fn unlisted(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.nodes.len())
+            .filter(|&id| self.is_dir(id) && !self.nodes[id].listed)
+            .map(|id| self.path(id))
+    }
+
+    // Listing in the style of the puzzle, entries sorted by name.
+    fn listing(&self) -> String {
+        let sizes = self.collect_dir_sizes();
+        let mut s = String::new();
+        let mut stack = vec![(ROOT, 0)];
+        while let Some((id, depth)) = stack.pop() {
+            let n = &self.nodes[id];
+            let kind = match n.stat {
+                Stat::Dir => "dir",
+                Stat::File(_) => "file",
+            };
+            s.push_str(&format!(
+                "{}- {} ({}, size={})\n",
+                "  ".repeat(depth),
+                n.name,
+                kind,
+                sizes[id]
+            ));
+            let mut children = n.children.clone();
+            children.sort_by(|&a, &b| self.nodes[b].name.cmp(&self.nodes[a].name));
+            stack.extend(children.into_iter().map(|c| (c, depth + 1)));
+        }
+        s
+    }
 }
 
-fn tree(input: &str) -> Option<Vec<Tree>> {
-    let mut path = String::from("/");
-    let mut tree = DirMap::new();
+fn tree(input: &str) -> Result<Fs> {
+    let mut fs = Fs::new();
+    let mut cwd = ROOT;
 
-    for ll in proc(input) {
+    for (line, ll) in proc(input)? {
+        let err = |msg: String| anyhow!("line {}: {}", line, msg);
         match ll {
             Log::Cd(dir) => {
-                if dir == ".." {
-                    let p = path.rfind('/').unwrap();
-                    if p > 1 {
-                        path.truncate(p);
-                    } else {
-                        path.truncate(1); // at root
-                    }
-                } else if dir == "/" {
-                    path.truncate(1); // at root
-                } else {
-                    if path != "/" {
-                        path.push('/'); // non-root
-                    }
-                    path.push_str(&dir);
+                cwd = match dir.as_str() {
+                    "/" => ROOT,
+                    ".." if cwd == ROOT => return Err(err("cd .. at the root".to_string())),
+                    ".." => fs.nodes[cwd].parent,
+                    name => match fs.child(cwd, name) {
+                        Some(id) if fs.is_dir(id) => id,
+                        Some(id) => return Err(err(format!("cd into file {}", fs.path(id)))),
+                        None => {
+                            let path = path_append(&fs.path(cwd), name);
+                            return Err(err(format!("cd into unknown directory {}", path)));
+                        }
+                    },
                 }
             }
-            Log::Ls(ents) => {
-                tree.insert(path.clone(), ents);
-            }
+            Log::Ls(ents) => fs.list(cwd, ents).map_err(|e| err(e.to_string()))?,
         };
     }
 
-    tree_impl(&tree, "/")
+    Ok(fs)
 }
 
-type DirMap = HashMap<String, Vec<DirEnt>>;
-
-fn tree_impl(m: &DirMap, path: &str) -> Option<Vec<Tree>> {
-    Some(
-        m.get(path)?
-            .iter()
-            .filter_map(|de| match de.stat {
-                Stat::Dir => Some(Tree::Dir(tree_impl(m, &path_append(path, &de.name))?)),
-                Stat::File(siz) => Some(Tree::File(siz)),
-            })
-            .collect(),
-    )
-}
-
-fn proc(input: &str) -> Vec<Log> {
+// Commands of the log with their line numbers.
+fn proc(input: &str) -> Result<Vec<(usize, Log)>> {
     let mut log = Vec::new();
 
-    for line in input.lines() {
+    for (i, line) in input.lines().enumerate() {
         if let Some(cd) = line.strip_prefix("$ cd ") {
             // $ cd «path»
-            log.push(Log::Cd(String::from(cd)));
+            log.push((i + 1, Log::Cd(String::from(cd))));
+        } else if line == "$ ls" {
+            log.push((i + 1, Log::Ls(Vec::new())));
         } else if let Some((pfx, name)) = line.split_once(' ') {
-            let mut addl = |stat, name| {
-                let de = DirEnt {
+            let stat = if pfx == "dir" {
+                Stat::Dir
+            } else if let Ok(siz) = pfx.parse() {
+                Stat::File(siz)
+            } else {
+                bail!("line {}: unrecognized line {:?}", i + 1, line);
+            };
+            if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                bail!("line {}: invalid name {:?}", i + 1, name);
+            }
+            match log.last_mut() {
+                Some((_, Log::Ls(list))) => list.push(DirEnt {
                     stat,
                     name: String::from(name),
-                };
-                match log.last_mut().unwrap() {
-                    Log::Ls(list) => {
-                        list.push(de);
-                    }
-                    _ => {
-                        log.push(Log::Ls(vec![de]));
-                    }
-                }
-            };
-            if pfx == "dir" {
-                addl(Stat::Dir, name);
-            } else if let Ok(siz) = pfx.parse() {
-                addl(Stat::File(siz), name);
+                }),
+                _ => bail!("line {}: listing without ls", i + 1),
             }
+        } else if !line.is_empty() {
+            bail!("line {}: unrecognized line {:?}", i + 1, line);
         }
     }
 
-    log
+    Ok(log)
 }
 
 #[derive(Debug)]
@@ -138,7 +308,7 @@ enum Log {
     Ls(Vec<DirEnt>), // ls output
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum Stat {
     Dir,
     File(usize),
@@ -158,3 +328,218 @@ fn path_append(base: &str, path: &str) -> String {
     r.push_str(path);
     r
 }
+
+// Log of a session exploring a local directory, listing each directory
+// once, and the size of each directory as seen by std::fs.
+fn walk_log(root: &Path) -> Result<(String, Vec<(String, usize)>)> {
+    struct Frame {
+        path: String,
+        subdirs: Vec<(String, PathBuf)>,
+        total: usize,
+    }
+
+    let mut log = String::from("$ cd /\n");
+    let list = |log: &mut String, dir: &Path, path: String| -> Result<Frame> {
+        let mut ents = Vec::new();
+        for e in fs::read_dir(dir).map_err(|e| anyhow!("reading {:?}: {}", dir, e))? {
+            let e = e?;
+            let name = e
+                .file_name()
+                .into_string()
+                .map_err(|n| anyhow!("name {:?} in {:?} is not UTF-8", n, dir))?;
+            if name.contains(['\n', '\r']) {
+                bail!("name {:?} in {:?} does not fit on a line", name, dir);
+            }
+            // symbolic links and special files are left out
+            let meta = e.metadata()?;
+            if meta.is_dir() {
+                ents.push((name, None, e.path()));
+            } else if meta.is_file() {
+                ents.push((name, Some(meta.len() as usize), e.path()));
+            }
+        }
+        ents.sort();
+
+        log.push_str("$ ls\n");
+        let mut frame = Frame {
+            path,
+            subdirs: Vec::new(),
+            total: 0,
+        };
+        for (name, size, p) in ents {
+            match size {
+                None => {
+                    log.push_str(&format!("dir {}\n", name));
+                    frame.subdirs.push((name, p));
+                }
+                Some(size) => {
+                    log.push_str(&format!("{} {}\n", size, name));
+                    frame.total += size;
+                }
+            }
+        }
+        frame.subdirs.reverse();
+        Ok(frame)
+    };
+
+    let mut sizes = Vec::new();
+    let mut stack = vec![list(&mut log, root, String::from("/"))?];
+    while let Some(top) = stack.last_mut() {
+        if let Some((name, p)) = top.subdirs.pop() {
+            let path = path_append(&top.path, &name);
+            log.push_str(&format!("$ cd {}\n", name));
+            let frame = list(&mut log, &p, path)?;
+            stack.push(frame);
+            continue;
+        }
+        let done = stack.pop().unwrap();
+        sizes.push((done.path, done.total));
+        if let Some(parent) = stack.last_mut() {
+            parent.total += done.total;
+            log.push_str("$ cd ..\n");
+        }
+    }
+
+    Ok((log, sizes))
+}
+
+// Generates the log of a local directory, and checks that parsing it
+// back gives the same directory sizes.
+fn check_walk(root: &Path) -> Result<String> {
+    let (log, mut expected) = walk_log(root)?;
+    let mut sizes = tree(&log)?.du(ROOT);
+    expected.sort();
+    sizes.sort();
+    if let Some((e, s)) = std::iter::zip(&expected, &sizes).find(|(e, s)| e != s) {
+        bail!("{} has size {} on disk, {} in the log", e.0, e.1, s.1);
+    }
+    if expected.len() != sizes.len() {
+        bail!(
+            "{} directories on disk, {} in the log",
+            expected.len(),
+            sizes.len()
+        );
+    }
+    Ok(log)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE: &str = "\
+$ cd /
+$ ls
+dir a
+14848514 b.txt
+8504156 c.dat
+dir d
+$ cd a
+$ ls
+dir e
+29116 f
+2557 g
+62596 h.lst
+$ cd e
+$ ls
+584 i
+$ cd ..
+$ cd ..
+$ cd d
+$ ls
+4060174 j
+8033020 d.log
+5626152 d.ext
+7214296 k
+";
+
+    #[test]
+    fn day07_works() {
+        let fs = tree(SAMPLE).unwrap();
+        let du = fs.du(ROOT);
+        assert_eq!(du.last().unwrap(), &(String::from("/"), 48381165));
+        let a = fs.du(fs.lookup("/a").unwrap());
+        assert_eq!(
+            a,
+            [(String::from("/a/e"), 584), (String::from("/a"), 94853)]
+        );
+        let small: usize = du.iter().map(|d| d.1).filter(|&x| x <= 100000).sum();
+        assert_eq!(small, 95437);
+        assert_eq!(fs.size(fs.lookup("/a/e").unwrap()), 584);
+        assert_eq!(fs.size(fs.lookup("/d").unwrap()), 24933642);
+        assert_eq!(fs.path(fs.lookup("/a/e/i").unwrap()), "/a/e/i");
+        assert_eq!(fs.lookup("/a/x"), None);
+        assert_eq!(fs.unlisted().count(), 0);
+        assert!(fs.listing().starts_with(
+            "\
+- / (dir, size=48381165)
+  - a (dir, size=94853)
+    - e (dir, size=584)
+      - i (file, size=584)
+    - f (file, size=29116)
+"
+        ));
+    }
+
+    #[test]
+    fn inconsistent_logs() {
+        let relisted = format!("{}$ cd /\n$ ls\ndir a\n", SAMPLE);
+        let again = format!(
+            "{}$ cd /\n$ ls\n8504156 c.dat\n14848514 b.txt\ndir d\ndir a\n",
+            SAMPLE
+        );
+        assert!(tree(&again).is_ok());
+        for (log, msg) in [
+            (
+                relisted.as_str(),
+                "line 25: / listed again with different contents",
+            ),
+            ("$ cd /\n$ cd x\n", "line 2: cd into unknown directory /x"),
+            ("$ ls\n1 f\n$ cd f\n", "line 3: cd into file /f"),
+            ("$ cd ..\n", "line 1: cd .. at the root"),
+            ("$ ls\ndir a\n1 a\n", "line 1: / lists \"a\" twice"),
+            ("1 f\n", "line 1: listing without ls"),
+            ("$ ls\nx f\n", "line 2: unrecognized line \"x f\""),
+            ("$ ls\ndir a/b\n", "line 2: invalid name \"a/b\""),
+        ] {
+            assert_eq!(tree(log).unwrap_err().to_string(), msg);
+        }
+        let fs = tree("$ ls\ndir a\ndir b\n$ cd b\n$ ls\n").unwrap();
+        assert_eq!(fs.unlisted().collect::<Vec<_>>(), ["/a"]);
+    }
+
+    #[test]
+    fn walk_roundtrip() {
+        let root = std::env::temp_dir().join(format!("aoc22-day07-{}", std::process::id()));
+        let mut deep = root.join("deep");
+        for i in 0..150 {
+            deep.push(if i % 2 == 0 { "d" } else { "dir x" });
+        }
+        fs::create_dir_all(&deep).unwrap();
+        fs::write(deep.join("bottom"), [0; 10]).unwrap();
+        let names = ["dir y", "$ cd ..", "12 z", " lead", "ünïcødé", "a.b.c"];
+        for name in names {
+            fs::create_dir_all(root.join(name)).unwrap();
+            fs::write(root.join(name).join(name), vec![1; name.len()]).unwrap();
+        }
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("zero"), []).unwrap();
+
+        let log = check_walk(&root).unwrap();
+        let fs = tree(&log).unwrap();
+        let total: usize = names.iter().map(|n| n.len()).sum();
+        assert_eq!(fs.size(ROOT), 10 + total);
+        assert_eq!(fs.size(fs.lookup("/deep").unwrap()), 10);
+        assert_eq!(fs.size(fs.lookup("/$ cd ../$ cd ..").unwrap()), 7);
+        assert_eq!(fs.size(fs.lookup("/empty").unwrap()), 0);
+        assert!(log.contains("dir  lead\n"));
+        assert_eq!(fs.du(ROOT).len(), 1 + 1 + 150 + 6 + 1);
+
+        fs::write(root.join("new\nline"), []).unwrap();
+        assert!(check_walk(&root)
+            .unwrap_err()
+            .to_string()
+            .contains("does not fit on a line"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,9 +1,15 @@
+use crate::param::Param;
+use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 
-pub fn run(input: &str) -> anyhow::Result<String> {
+// Keep worry levels modulo the product of the divisors.
+const REDUCE: Param<bool> = Param::new("day11.reduce", true);
+
+pub fn run(input: &str) -> Result<String> {
     let monkeys = parse(input);
-    let p1 = sim(&monkeys, 3, 20);
-    let p2 = sim(&monkeys, 1, 10000);
+    let reduce = REDUCE.get()?;
+    let p1 = sim(&monkeys, 3, 20, reduce)?;
+    let p2 = sim(&monkeys, 1, 10000, reduce)?;
     Ok(format!("{} {}", p1, p2))
 }
 
@@ -11,19 +17,36 @@ fn parse(input: &str) -> Vec<Monkey> {
     input.split("\n\n").filter_map(Monkey::parse).collect()
 }
 
-fn sim(horde: &[Monkey], wdiv: usize, n: usize) -> usize {
+fn sim(horde: &[Monkey], wdiv: usize, n: usize, reduce: bool) -> Result<usize> {
     let mut horde = horde.to_vec();
-    let ring: usize = horde.iter().map(|m| m.div).product();
+    let ring = if reduce {
+        let r = horde
+            .iter()
+            .try_fold(1usize, |acc, m| acc.checked_mul(m.div))
+            .ok_or_else(|| anyhow!("divisor product overflow"))?;
+        Some(r)
+    } else {
+        None
+    };
     let mut inspects = HashMap::new();
     for _ in 0..n {
-        round(&mut horde, ring, wdiv, &mut inspects);
+        round(&mut horde, ring, wdiv, &mut inspects)?;
     }
     let mut v: Vec<usize> = inspects.values().copied().collect();
     v.sort_by_key(|x| std::cmp::Reverse(*x));
-    v[0] * v[1]
+    if v.len() < 2 {
+        return Err(anyhow!("need at least two monkeys"));
+    }
+    v[0].checked_mul(v[1])
+        .ok_or_else(|| anyhow!("monkey business overflow"))
 }
 
-fn round(horde: &mut [Monkey], ring: usize, wdiv: usize, inspects: &mut HashMap<usize, usize>) {
+fn round(
+    horde: &mut [Monkey],
+    ring: Option<usize>,
+    wdiv: usize,
+    inspects: &mut HashMap<usize, usize>,
+) -> Result<()> {
     for i in 0..horde.len() {
         let v = horde[i].items.split_off(0);
         inspects
@@ -32,8 +55,9 @@ fn round(horde: &mut [Monkey], ring: usize, wdiv: usize, inspects: &mut HashMap<
             .or_insert(v.len());
         for item in v {
             let m = &horde[i];
-            let n = (m.op.apply(item) % ring) / wdiv;
-            let j = if n % m.div == 0 {
+            let w = m.op.apply(item)?;
+            let n = ring.map_or(w, |r| w % r) / wdiv;
+            let j = if n.is_multiple_of(m.div) {
                 m.if_true
             } else {
                 m.if_false
@@ -41,6 +65,7 @@ fn round(horde: &mut [Monkey], ring: usize, wdiv: usize, inspects: &mut HashMap<
             horde[j].items.push(n);
         }
     }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -107,11 +132,63 @@ enum Op {
 }
 
 impl Op {
-    fn apply(&self, n: usize) -> usize {
+    fn apply(&self, n: usize) -> Result<usize> {
         match *self {
-            Op::Add(m) => n + m,
-            Op::Mul(m) => n * m,
-            Op::Square => n * n,
+            Op::Add(m) => n.checked_add(m),
+            Op::Mul(m) => n.checked_mul(m),
+            Op::Square => n.checked_mul(n),
         }
+        .ok_or_else(|| anyhow!("worry level overflow in {:?} applied to {}", self, n))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn day11_works() {
+        let monkeys = parse(SAMPLE);
+        assert_eq!(sim(&monkeys, 3, 20, true).ok(), Some(10605));
+        assert_eq!(sim(&monkeys, 1, 10000, true).ok(), Some(2713310158));
+        assert_eq!(sim(&monkeys, 3, 20, false).ok(), Some(10605));
+        assert!(Op::Square.apply(usize::MAX / 2).is_err());
     }
+
+    #[test]
+    fn overflow_without_reduce() {
+        let monkeys = parse(SAMPLE);
+        let e = sim(&monkeys, 1, 10000, false).unwrap_err();
+        assert!(e.to_string().starts_with("worry level overflow"), "{}", e);
+    }
+
+    const SAMPLE: &str = "\
+Monkey 0:
+  Starting items: 79, 98
+  Operation: new = old * 19
+  Test: divisible by 23
+    If true: throw to monkey 2
+    If false: throw to monkey 3
+
+Monkey 1:
+  Starting items: 54, 65, 75, 74
+  Operation: new = old + 6
+  Test: divisible by 19
+    If true: throw to monkey 2
+    If false: throw to monkey 0
+
+Monkey 2:
+  Starting items: 79, 60, 97
+  Operation: new = old * old
+  Test: divisible by 13
+    If true: throw to monkey 1
+    If false: throw to monkey 3
+
+Monkey 3:
+  Starting items: 74
+  Operation: new = old + 3
+  Test: divisible by 17
+    If true: throw to monkey 0
+    If false: throw to monkey 1
+";
 }
@@ -1,121 +1,408 @@
+use crate::param::Param;
 use anyhow::{anyhow, bail, Result};
 use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+
+const IMPORT: Param<String> = Param::new("day13.import", String::new());
+const EXPORT: Param<String> = Param::new("day13.export", String::new());
 
 pub fn run(input: &str) -> Result<String> {
-    let trees = parse(input)?;
+    // packets from a JSON file written by another tool replace the input
+    let path = IMPORT.get()?;
+    let json = match path.as_str() {
+        "" => None,
+        path => Some(fs::read_to_string(path).map_err(|e| anyhow!("reading {:?}: {}", path, e))?),
+    };
+    let stream = || -> Box<dyn Iterator<Item = Result<Tree>> + '_> {
+        match &json {
+            Some(json) => Box::new(
+                import(json).map(|t| t.map_err(|e| anyhow!("packets in {:?}: {}", path, e))),
+            ),
+            None => Box::new(packets(input)),
+        }
+    };
+
+    let path = EXPORT.get()?;
+    if !path.is_empty() {
+        fs::write(&path, export(stream())?).map_err(|e| anyhow!("writing {:?}: {}", path, e))?;
+    }
 
-    let p1 = check_sort(&trees);
-    let p2 = decoder_key(&trees);
+    let p1 = check_sort(stream())?;
+    let p2 = decoder_key(stream())?;
     Ok(format!("{} {}", p1, p2))
 }
 
-fn check_sort(trees: &[Tree]) -> usize {
-    trees
-        .chunks(2)
-        .enumerate()
-        .filter_map(|(i, v)| (v.len() == 2 && Tree::order_ok(&v[0], &v[1])).then_some(i + 1))
-        .sum()
-}
-
-fn decoder_key(trees: &[Tree]) -> usize {
-    let mut v = Vec::from(trees);
-    let divider = |n| Tree::List(vec![Tree::List(vec![Tree::Num(n)])]);
-    let d2 = divider(2);
-    let d6 = divider(6);
-    v.push(d2.clone());
-    v.push(d6.clone());
-    v.sort();
-
-    let idx = |item| {
-        v.iter()
-            .enumerate()
-            .find(|(_, x)| x == &item)
-            .map(|(i, _)| i + 1)
-            .unwrap()
-    };
-    idx(&d2) * idx(&d6)
+fn check_sort(mut packets: impl Iterator<Item = Result<Tree>>) -> Result<usize> {
+    let mut sum = 0;
+    for i in 1.. {
+        let (Some(l), Some(r)) = (packets.next().transpose()?, packets.next().transpose()?) else {
+            break;
+        };
+        if crate::verbose() {
+            println!("== Pair {} ==\n{}\n{}", i, l.pretty(), r.pretty());
+            println!("{}", Tree::explain(&l, &r));
+        }
+        if Tree::order_ok(&l, &r) {
+            sum += i;
+        }
+    }
+    Ok(sum)
 }
 
-fn parse(input: &str) -> Result<Vec<Tree>> {
+// Position of the dividers if they were sorted in with the packets.
+fn decoder_key(packets: impl Iterator<Item = Result<Tree>>) -> Result<usize> {
+    let d2 = Tree::parse("[[2]]").unwrap();
+    let d6 = Tree::parse("[[6]]").unwrap();
+    let (mut i2, mut i6) = (1, 2);
+    for t in packets {
+        let t = t?;
+        if t < d2 {
+            i2 += 1;
+        }
+        if t < d6 {
+            i6 += 1;
+        }
+    }
+    Ok(i2 * i6)
+}
+
+// Packets of the input, one per non-empty line, parsed as needed.
+fn packets(input: &str) -> impl Iterator<Item = Result<Tree>> + '_ {
     input
         .lines()
-        .filter(|line| !line.is_empty())
-        .map(Tree::parse)
-        .collect()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| Tree::parse(line).map_err(|e| anyhow!("line {}: {}", i + 1, e)))
+}
+
+// Packets from a JSON array of them, such as [[1,[2]],[3]], parsed
+// as needed. Only arrays and non-negative integers are accepted.
+fn import(json: &str) -> impl Iterator<Item = Result<Tree>> + '_ {
+    let (mut pos, mut count, mut done) = (0, 0, false);
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let r = import_next(json, &mut pos, count).transpose();
+        count += 1;
+        done = !matches!(r, Some(Ok(_)));
+        r
+    })
+}
+
+fn import_next(json: &str, pos: &mut usize, count: usize) -> Result<Option<Tree>> {
+    let unexpected = |i: usize, expected: &str| match json[i..].chars().next() {
+        Some(c) => anyhow!(
+            "unexpected {:?} at {}, expected {}",
+            c,
+            Source::Json.at(json, i),
+            expected
+        ),
+        None => anyhow!("unexpected end of document, expected {}", expected),
+    };
+    let eat = |pos: &mut usize, c: char| {
+        *pos = skip_json_ws(json, *pos);
+        let ok = json[*pos..].starts_with(c);
+        if ok {
+            *pos += 1;
+        }
+        ok
+    };
+    let closed = if count == 0 {
+        if !eat(pos, '[') {
+            return Err(unexpected(*pos, "'['"));
+        }
+        eat(pos, ']')
+    } else if eat(pos, ']') {
+        true
+    } else if eat(pos, ',') {
+        false
+    } else {
+        return Err(unexpected(*pos, "',' or ']'"));
+    };
+    if closed {
+        *pos = skip_json_ws(json, *pos);
+        if *pos < json.len() {
+            return Err(unexpected(*pos, "the end of the document"));
+        }
+        return Ok(None);
+    }
+    let (t, end) = Tree::read(json, *pos, Source::Json)?;
+    *pos = end;
+    Ok(Some(t))
+}
+
+fn skip_json_ws(json: &str, i: usize) -> usize {
+    json[i..]
+        .find(|c| !matches!(c, ' ' | '\t' | '\n' | '\r'))
+        .map_or(json.len(), |n| i + n)
+}
+
+// The packets as a JSON array, numbers without their leading zeros.
+fn export(packets: impl Iterator<Item = Result<Tree>>) -> Result<String> {
+    let mut s = String::from("[");
+    for (i, t) in packets.enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        t?.write_with(&mut s, Number::json)?;
+    }
+    s.push_str("]\n");
+    Ok(s)
 }
 
 #[derive(Debug, Eq, Clone)]
 enum Tree {
-    Num(u32),
+    Num(Number),
     List(Vec<Tree>),
 }
 
 impl Tree {
+    // Parses one packet, which must not contain whitespace so that
+    // Display gives back the exact line.
     fn parse(line: &str) -> Result<Tree> {
-        let mut tokens = Vec::new();
-        let mut is_num = false;
-        let mut num: u32 = 0;
-        let mut nopen = 0;
-        for c in line.chars() {
-            if c == ',' || c == ']' {
-                if is_num {
-                    tokens.push(Token::Num(num));
+        let (t, end) = Self::read(line, 0, Source::Line)?;
+        match line[end..].chars().next() {
+            Some(c) => bail!(
+                "unexpected {:?} at {}, expected {}",
+                c,
+                Source::Line.at(line, end),
+                Expect::End
+            ),
+            None => Ok(t),
+        }
+    }
+
+    // Reads one packet from s, starting at byte offset start, and
+    // returns it with the offset just past it.
+    fn read(s: &str, start: usize, src: Source) -> Result<(Tree, usize)> {
+        let mut stack: Vec<Vec<Tree>> = Vec::new();
+        let mut expect = Expect::Value;
+        let mut it = s[start..]
+            .char_indices()
+            .map(|(i, c)| (start + i, c))
+            .peekable();
+        while let Some((i, c)) = it.next() {
+            let value = match (c, expect) {
+                (' ' | '\t' | '\n' | '\r', _) if src == Source::Json => None,
+                ('[', Expect::Value | Expect::ValueOrClose) => {
+                    stack.push(Vec::new());
+                    expect = Expect::ValueOrClose;
+                    None
                 }
-                is_num = false;
-                num = 0;
-            }
-            if c == '[' {
-                tokens.push(Token::Open);
-                nopen += 1;
-            } else if c == ']' {
-                tokens.push(Token::Close);
-                nopen -= 1;
-                if nopen < 0 {
-                    bail!("unmatched parenthesis");
+                ('0'..='9', Expect::Value | Expect::ValueOrClose) => {
+                    let mut end = i + 1;
+                    while let Some((j, _)) = it.next_if(|(_, c)| c.is_ascii_digit()) {
+                        end = j + 1;
+                    }
+                    if src == Source::Json && c == '0' && end > i + 1 {
+                        bail!("leading zero at {}", src.at(s, i));
+                    }
+                    Some((Tree::Num(Number(s[i..end].to_string())), end))
+                }
+                (']', Expect::ValueOrClose | Expect::CommaOrClose) => {
+                    Some((Tree::List(stack.pop().unwrap()), i + 1))
+                }
+                (',', Expect::CommaOrClose) => {
+                    expect = Expect::Value;
+                    None
+                }
+                ('[' | ']' | ',' | '0'..='9', _) => {
+                    bail!(
+                        "unexpected {:?} at {}, expected {}",
+                        c,
+                        src.at(s, i),
+                        expect
+                    )
+                }
+                _ => bail!("invalid character {:?} at {}", c, src.at(s, i)),
+            };
+            if let Some((v, end)) = value {
+                match stack.last_mut() {
+                    Some(list) => {
+                        list.push(v);
+                        expect = Expect::CommaOrClose;
+                    }
+                    None => return Ok((v, end)),
                 }
-            } else if c == ',' {
-                // pass
-            } else if let Some(i) = c.to_digit(10) {
-                num = num * 10 + i;
-                is_num = true;
-            } else {
-                bail!("invalid char {}", c);
             }
         }
+        bail!("unexpected end of {}, expected {}", src, expect)
+    }
 
-        let mut stack = vec![Tree::List(Vec::new())];
-        for tok in tokens {
-            match tok {
-                Token::Open => stack.push(Tree::List(Vec::new())),
-                Token::Close => {
-                    let last = stack.pop().unwrap();
-                    match stack.last_mut().unwrap() {
-                        Tree::List(v) => v.push(last),
-                        _ => panic!("impossible"),
+    fn order_ok(l: &Tree, r: &Tree) -> bool {
+        l <= r
+    }
+
+    // Step by step comparison in the words of the puzzle.
+    fn explain(l: &Tree, r: &Tree) -> Explanation {
+        let mut path = Vec::new();
+        let mut lines = Vec::new();
+        let order = Self::trace(l, r, 0, &mut path, &mut lines);
+        Explanation {
+            order: order.unwrap_or(Ordering::Equal),
+            path: order.map(|_| path),
+            lines,
+        }
+    }
+
+    // Returns the ordering once decided, with path holding the list
+    // indices that lead to the deciding items.
+    fn trace(
+        l: &Tree,
+        r: &Tree,
+        depth: usize,
+        path: &mut Vec<usize>,
+        lines: &mut Vec<String>,
+    ) -> Option<Ordering> {
+        let say = |lines: &mut Vec<String>, d: usize, msg: String| {
+            lines.push(format!("{}- {}", "  ".repeat(d), msg))
+        };
+        say(lines, depth, format!("Compare {} vs {}", l, r));
+        let (msg, order) = match (l, r) {
+            (Tree::Num(a), Tree::Num(b)) => match a.cmp(b) {
+                Ordering::Equal => return None,
+                o => (
+                    if o == Ordering::Less {
+                        "Left side is smaller"
+                    } else {
+                        "Right side is smaller"
+                    },
+                    o,
+                ),
+            },
+            (Tree::List(av), Tree::List(bv)) => {
+                for (i, (a, b)) in std::iter::zip(av, bv).enumerate() {
+                    path.push(i);
+                    if let Some(o) = Self::trace(a, b, depth + 1, path, lines) {
+                        return Some(o);
                     }
+                    path.pop();
                 }
-                Token::Num(n) => {
-                    let m = stack.last_mut().unwrap();
-                    match m {
-                        Tree::List(v) => v.push(Tree::Num(n)),
-                        _ => panic!("impossible"),
-                    }
+                match av.len().cmp(&bv.len()) {
+                    Ordering::Equal => return None,
+                    Ordering::Less => ("Left side ran out of items", Ordering::Less),
+                    Ordering::Greater => ("Right side ran out of items", Ordering::Greater),
                 }
             }
+            (Tree::Num(_), Tree::List(_)) => {
+                say(
+                    lines,
+                    depth + 1,
+                    format!("Mixed types; convert left to [{}] and retry comparison", l),
+                );
+                return Self::trace(&Tree::List(vec![l.clone()]), r, depth + 1, path, lines);
+            }
+            (Tree::List(_), Tree::Num(_)) => {
+                say(
+                    lines,
+                    depth + 1,
+                    format!("Mixed types; convert right to [{}] and retry comparison", r),
+                );
+                return Self::trace(l, &Tree::List(vec![r.clone()]), depth + 1, path, lines);
+            }
+        };
+        let verdict = if order == Ordering::Less {
+            "in the right order"
+        } else {
+            "not in the right order"
+        };
+        say(
+            lines,
+            depth + 1,
+            format!("{}, so inputs are {}", msg, verdict),
+        );
+        Some(order)
+    }
+
+    // Multi-line form: lists holding only numbers stay on one line,
+    // other lists have one item per line, indented by two spaces.
+    fn pretty(&self) -> String {
+        let mut s = String::new();
+        // multi-line lists being written, with the items still to come
+        let mut open: Vec<std::slice::Iter<Tree>> = Vec::new();
+        let mut t = self;
+        loop {
+            let mut sep = ",\n";
+            match t {
+                Tree::List(v) if v.iter().any(|t| matches!(t, Tree::List(_))) => {
+                    s.push('[');
+                    open.push(v.iter());
+                    sep = "\n";
+                }
+                _ => s.push_str(&t.to_string()),
+            }
+            t = loop {
+                let Some(items) = open.last_mut() else {
+                    return s;
+                };
+                if let Some(next) = items.next() {
+                    s.push_str(sep);
+                    s.push_str(&"  ".repeat(open.len()));
+                    break next;
+                }
+                open.pop();
+                s.push('\n');
+                s.push_str(&"  ".repeat(open.len()));
+                s.push(']');
+                sep = ",\n";
+            };
         }
+    }
+}
 
-        stack.pop().ok_or_else(|| anyhow!("empty input"))
+// Packets can nest deeper than the call stack allows, so Drop, Display
+// and Ord walk them with explicit stacks.
+impl Drop for Tree {
+    fn drop(&mut self) {
+        let mut stack = match self {
+            Tree::List(v) => std::mem::take(v),
+            Tree::Num(_) => return,
+        };
+        while let Some(mut t) = stack.pop() {
+            if let Tree::List(v) = &mut t {
+                stack.append(v);
+            }
+        }
     }
+}
 
-    fn order_ok(l: &Tree, r: &Tree) -> bool {
-        l <= r
+impl Tree {
+    // Writes the packet, numbers as given by num.
+    fn write_with(&self, f: &mut dyn fmt::Write, num: fn(&Number) -> &str) -> fmt::Result {
+        // lists being written, with the items still to come
+        let mut open: Vec<std::slice::Iter<Tree>> = Vec::new();
+        let mut t = self;
+        loop {
+            let mut sep = ",";
+            match t {
+                Tree::Num(n) => f.write_str(num(n))?,
+                Tree::List(v) => {
+                    f.write_str("[")?;
+                    open.push(v.iter());
+                    sep = "";
+                }
+            }
+            t = loop {
+                let Some(items) = open.last_mut() else {
+                    return Ok(());
+                };
+                if let Some(next) = items.next() {
+                    f.write_str(sep)?;
+                    break next;
+                }
+                open.pop();
+                f.write_str("]")?;
+                sep = ",";
+            };
+        }
     }
+}
 
-    fn list_order(av: &[Self], bv: &[Self]) -> Ordering {
-        std::iter::zip(av, bv)
-            .map(|(a, b)| a.cmp(b))
-            .find(|&x| x != Ordering::Equal)
-            .unwrap_or_else(|| av.len().cmp(&bv.len()))
+impl fmt::Display for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_with(f, |n| &n.0)
     }
 }
 
@@ -127,12 +414,31 @@ impl PartialOrd for Tree {
 
 impl Ord for Tree {
     fn cmp(&self, other: &Self) -> Ordering {
-        match (self, other) {
-            (Self::Num(a), Self::Num(b)) => a.cmp(b),
-            (Self::List(av), Self::List(bv)) => Self::list_order(av, bv),
-            (Self::Num(a), Self::List(bv)) => Self::list_order(&[Self::Num(*a)], bv),
-            (Self::List(av), Self::Num(b)) => Self::list_order(av, &[Self::Num(*b)]),
+        // pairs of lists being compared, with the items still to come
+        let one = std::slice::from_ref;
+        let mut stack = vec![(one(self).iter(), one(other).iter())];
+        while let Some((av, bv)) = stack.last_mut() {
+            let (a, b) = match (av.next(), bv.next()) {
+                (Some(a), Some(b)) => (a, b),
+                (None, None) => {
+                    stack.pop();
+                    continue;
+                }
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+            };
+            let lists = match (a, b) {
+                (Self::Num(x), Self::Num(y)) => match x.cmp(y) {
+                    Ordering::Equal => continue,
+                    o => return o,
+                },
+                (Self::List(xv), Self::List(yv)) => (xv.iter(), yv.iter()),
+                (Self::Num(_), Self::List(yv)) => (one(a).iter(), yv.iter()),
+                (Self::List(xv), Self::Num(_)) => (xv.iter(), one(b).iter()),
+            };
+            stack.push(lists);
         }
+        Ordering::Equal
     }
 }
 
@@ -142,20 +448,308 @@ impl PartialEq for Tree {
     }
 }
 
+struct Explanation {
+    order: Ordering,
+    path: Option<Vec<usize>>, // None if the packets compare equal
+    lines: Vec<String>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        match &self.path {
+            Some(path) => {
+                write!(f, "Decided at packet")?;
+                for i in path {
+                    write!(f, "[{}]", i)?;
+                }
+                writeln!(f, ": {:?}", self.order)
+            }
+            None => writeln!(f, "Packets are equal"),
+        }
+    }
+}
+
+// Non-negative integer of any width, kept as the digits it was written
+// with so that leading zeros survive a round trip.
+#[derive(Debug, Clone, Eq)]
+struct Number(String);
+
+impl Number {
+    fn significant(&self) -> &str {
+        self.0.trim_start_matches('0')
+    }
+
+    // JSON numbers have no leading zeros.
+    fn json(&self) -> &str {
+        match self.significant() {
+            "" => "0",
+            s => s,
+        }
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (self.significant(), other.significant());
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Where a packet is read from: a line of the input, or a JSON document
+// which may have whitespace between the tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Line,
+    Json,
+}
+
+impl Source {
+    // Position of byte offset i, with columns counted in characters.
+    fn at(self, s: &str, i: usize) -> String {
+        let line_start = s[..i].rfind('\n').map_or(0, |n| n + 1);
+        let col = s[line_start..i].chars().count() + 1;
+        match self {
+            Source::Line => format!("column {}", col),
+            Source::Json => format!("line {}, column {}", s[..i].matches('\n').count() + 1, col),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Line => "packet",
+            Source::Json => "document",
+        })
+    }
+}
+
+// What the parser accepts next.
 #[derive(Debug, Clone, Copy)]
-enum Token {
-    Open,
-    Close,
-    Num(u32),
+enum Expect {
+    Value,
+    ValueOrClose,
+    CommaOrClose,
+    End,
+}
+
+impl fmt::Display for Expect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Expect::Value => "a number or '['",
+            Expect::ValueOrClose => "a number, '[' or ']'",
+            Expect::CommaOrClose => "',' or ']'",
+            Expect::End => "the end of the packet",
+        })
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn sample() -> Vec<Tree> {
+        packets(SAMPLE).collect::<Result<_>>().unwrap()
+    }
+
     #[test]
     fn day13_works() {
-        assert_eq!(check_sort(&parse(SAMPLE).unwrap()), 13);
+        assert_eq!(check_sort(packets(SAMPLE)).unwrap(), 13);
+        assert_eq!(decoder_key(packets(SAMPLE)).unwrap(), 140);
+        assert_eq!(decoder_key(sample().into_iter().map(Ok)).unwrap(), 140);
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        for line in SAMPLE
+            .lines()
+            .filter(|l| !l.is_empty())
+            .chain(["7", "[[],007,[0]]"])
+        {
+            let t = Tree::parse(line).unwrap();
+            assert_eq!(t.to_string(), line);
+            let flat: String = t.pretty().split_whitespace().collect();
+            assert_eq!(flat, line);
+        }
+        let t = Tree::parse("[1,[2,[3,4]],[],5]").unwrap();
+        assert_eq!(
+            t.pretty(),
+            "[\n  1,\n  [\n    2,\n    [3,4]\n  ],\n  [],\n  5\n]"
+        );
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let n = 100_000;
+        let line = format!("{}7{}", "[".repeat(n), "]".repeat(n));
+        let t = Tree::parse(&line).unwrap();
+        assert_eq!(t.to_string(), line);
+        let u = Tree::parse(&format!("{}8{}", "[".repeat(n), "]".repeat(n))).unwrap();
+        assert!(t < u);
+        assert_eq!(t.cmp(&t), Ordering::Equal);
+        let json = export(std::iter::once(Ok(t))).unwrap();
+        let back = import(&json).next().unwrap().unwrap();
+        assert_eq!(back.to_string(), line);
+        // the indentation grows with the depth, so keep this one smaller
+        let t = Tree::parse(&format!("{}7{}", "[".repeat(2000), "]".repeat(2000))).unwrap();
+        let pretty = t.pretty();
+        assert_eq!(pretty.lines().count(), 2 * 2000 - 1);
+        assert_eq!(pretty.split_whitespace().collect::<String>(), t.to_string());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (line, msg) in [
+            ("", "unexpected end of packet, expected a number or '['"),
+            ("]", "unexpected ']' at column 1, expected a number or '['"),
+            (
+                "[1]]",
+                "unexpected ']' at column 4, expected the end of the packet",
+            ),
+            (
+                "[1,]",
+                "unexpected ']' at column 4, expected a number or '['",
+            ),
+            ("[1[2]]", "unexpected '[' at column 3, expected ',' or ']'"),
+            (
+                "[,1]",
+                "unexpected ',' at column 2, expected a number, '[' or ']'",
+            ),
+            ("[[1]", "unexpected end of packet, expected ',' or ']'"),
+            (
+                "[1]2",
+                "unexpected '2' at column 4, expected the end of the packet",
+            ),
+            ("[-1]", "invalid character '-' at column 2"),
+            ("[1,é]", "invalid character 'é' at column 4"),
+            ("[1, 2]", "invalid character ' ' at column 4"),
+            (" [1]", "invalid character ' ' at column 1"),
+        ] {
+            assert_eq!(Tree::parse(line).unwrap_err().to_string(), msg);
+        }
+        assert_eq!(
+            check_sort(packets("[1]\n\n[x]")).unwrap_err().to_string(),
+            "line 3: invalid character 'x' at column 2"
+        );
+    }
+
+    #[test]
+    fn explain_works() {
+        let trees = sample();
+        let e = Tree::explain(&trees[2], &trees[3]);
+        assert_eq!(e.order, Ordering::Less);
+        assert_eq!(e.path, Some(vec![1, 0]));
+        assert_eq!(
+            e.lines,
+            [
+                "- Compare [[1],[2,3,4]] vs [[1],4]",
+                "  - Compare [1] vs [1]",
+                "    - Compare 1 vs 1",
+                "  - Compare [2,3,4] vs 4",
+                "    - Mixed types; convert right to [4] and retry comparison",
+                "    - Compare [2,3,4] vs [4]",
+                "      - Compare 2 vs 4",
+                "        - Left side is smaller, so inputs are in the right order",
+            ]
+        );
+        let e = Tree::explain(&trees[4], &trees[5]);
+        assert_eq!(e.order, Ordering::Greater);
+        assert_eq!(e.path, Some(vec![0, 0]));
+        assert!(e
+            .to_string()
+            .ends_with("Decided at packet[0][0]: Greater\n"));
+
+        let e = Tree::explain(&trees[6], &trees[7]);
+        assert_eq!(e.path, Some(vec![]));
+        assert_eq!(
+            e.lines.last().unwrap(),
+            "  - Left side ran out of items, so inputs are in the right order"
+        );
+        for pair in trees.chunks(2) {
+            assert_eq!(
+                Tree::explain(&pair[0], &pair[1]).order,
+                pair[0].cmp(&pair[1])
+            );
+        }
+        let t = Tree::parse("[1,[2]]").unwrap();
+        let e = Tree::explain(&t, &t);
+        assert_eq!((e.order, e.path), (Ordering::Equal, None));
+    }
+
+    #[test]
+    fn json_works() {
+        let json = export(packets(SAMPLE)).unwrap();
+        let lines: Vec<_> = SAMPLE.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(json, format!("[{}]\n", lines.join(",")));
+        let back = import(&json).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            back.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+            lines
+        );
+
+        let json = export(packets("[007,[0,000],10]")).unwrap();
+        assert_eq!(json, "[[7,[0,0],10]]\n");
+        let t = import(&json).next().unwrap().unwrap();
+        assert_eq!(t, Tree::parse("[007,[0,000],10]").unwrap());
+
+        let json = " [\n  [1, [ 2 ]],\n  3\n]\n";
+        let v: Vec<_> = import(json).map(|t| t.unwrap().to_string()).collect();
+        assert_eq!(v, ["[1,[2]]", "3"]);
+        assert_eq!(import("[]").count(), 0);
+
+        for (json, msg) in [
+            ("", "unexpected end of document, expected '['"),
+            ("{}", "unexpected '{' at line 1, column 1, expected '['"),
+            ("[[1]", "unexpected end of document, expected ',' or ']'"),
+            (
+                "[[1],]",
+                "unexpected ']' at line 1, column 6, expected a number or '['",
+            ),
+            (
+                "[[1]] x",
+                "unexpected 'x' at line 1, column 7, expected the end of the document",
+            ),
+            ("[[1,\n -1]]", "invalid character '-' at line 2, column 2"),
+            ("[[1.5]]", "invalid character '.' at line 1, column 4"),
+            ("[[\"x\"]]", "invalid character '\"' at line 1, column 3"),
+            ("[[01]]", "leading zero at line 1, column 3"),
+            ("[[1],\n [é]]", "invalid character 'é' at line 2, column 3"),
+            ("[[1", "unexpected end of document, expected ',' or ']'"),
+        ] {
+            let err = import(json).collect::<Result<Vec<_>>>().unwrap_err();
+            assert_eq!(err.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn wide_numbers() {
+        let big = Tree::parse("[99999999999999999999999999]").unwrap();
+        let bigger = Tree::parse("[100000000000000000000000000]").unwrap();
+        assert!(big < bigger);
+        assert!(Tree::parse("[4294967296]").unwrap() > Tree::parse("[4294967295]").unwrap());
+        assert_eq!(Tree::parse("[007]").unwrap(), Tree::parse("[7]").unwrap());
+        assert!(Tree::parse("[0]").unwrap() < Tree::parse("[00001]").unwrap());
     }
 
     const SAMPLE: &str = "\
@@ -1,75 +1,332 @@
-use anyhow::{anyhow, Result};
-use itertools::Itertools;
-use std::collections::HashMap;
+use crate::param::Param;
+use anyhow::{anyhow, bail, Result};
+use std::cmp::{max, min, Ordering};
+use std::collections::HashSet;
+
+const ROW: Param<Coord> = Param::new("day15.row", 2000000);
+const MAX: Param<Coord> = Param::new("day15.max", 4000000);
 
 pub fn run(input: &str) -> Result<String> {
     let rdg = parse(input);
-    if crate::verbose() {
+    let verbose = crate::verbose();
+    if verbose {
         for s in &rdg {
             println!("({},{}) range {}", s.p.0, s.p.1, s.range);
         }
     }
-    let p1 = count_no_beacon(&rdg, 2000000);
-    let p2 = scan_beacon(&rdg, 4000000).ok_or_else(|| anyhow!("not found"))?;
+    let p1 = count_no_beacon(&rdg, ROW.get()?);
+
+    let cov = Coverage::from(&rdg);
+    let max = MAX.get()?;
+    let area = Rect {
+        min: (0, 0),
+        max: (max, max),
+    };
+    if verbose {
+        println!("covered cells: {}", cov.area());
+        println!("uncovered in search area: {}", cov.uncovered_in(&area));
+    }
+    let p2 = scan_beacon(&cov, &area)?;
     Ok(format!("{} {}", p1, p2))
 }
 
-fn count_no_beacon(rdg: &[Sensor], yline: i32) -> usize {
-    rdg_spans(rdg, yline)
-        .map(|(lo, hi)| (hi - 1 - lo) as usize)
-        .sum()
+type Coord = i64;
+
+fn count_no_beacon(rdg: &[Sensor], yline: Coord) -> u64 {
+    let mut spans = IntervalSet::default();
+    for s in rdg {
+        let width = s.range - (s.p.1 - yline).abs();
+        if width >= 0 {
+            spans.insert(s.p.0 - width, s.p.0 + width + 1);
+        }
+    }
+
+    // beacons are always within the range of their sensor
+    let beacons: HashSet<_> = rdg
+        .iter()
+        .filter_map(|s| (s.b.1 == yline).then_some(s.b.0))
+        .collect();
+    spans.len() - beacons.len() as u64
 }
 
-fn scan_beacon(rdg: &[Sensor], max: i32) -> Option<usize> {
-    rdg.iter()
-        .flat_map(|s0| {
-            s0.boundary().filter(|&p| {
-                (0..=max).contains(&p.0)
-                    && (0..=max).contains(&p.1)
-                    && rdg.iter().all(|s1| !s1.is_seen(p))
-            })
-        })
-        .next()
-        .map(|(x, y)| (x as usize) * 4000000 + (y as usize))
+fn scan_beacon(cov: &Coverage, area: &Rect) -> Result<Coord> {
+    let gaps: Vec<_> = cov.gaps(area).take(2).collect();
+    match gaps[..] {
+        [(x, y)] => Ok(x * 4000000 + y),
+        [] => Err(anyhow!("not found")),
+        _ => bail!("more than one gap: {:?}", gaps),
+    }
 }
 
-fn rdg_spans(rdg: &[Sensor], yline: i32) -> impl Iterator<Item = (i32, i32)> {
-    let mut v = Vec::new();
-    for s in rdg {
-        let width = s.range - (s.p.1 - yline).abs();
-        if width > 0 {
-            v.push((s.p.0 - width, s.p.0 + width));
+// Set of integers stored as sorted, disjoint and non-adjacent half-open ranges.
+#[derive(Debug, Default, Clone)]
+struct IntervalSet(Vec<(Coord, Coord)>);
+
+impl IntervalSet {
+    fn insert(&mut self, lo: Coord, hi: Coord) {
+        if lo >= hi {
+            return;
         }
+        // ranges i..j overlap or touch lo..hi
+        let i = self.0.partition_point(|&(_, e)| e < lo);
+        let j = self.0.partition_point(|&(s, _)| s <= hi);
+        let (lo, hi) = if i < j {
+            (min(lo, self.0[i].0), max(hi, self.0[j - 1].1))
+        } else {
+            (lo, hi)
+        };
+        self.0.splice(i..j, [(lo, hi)]);
     }
 
-    spans_merged(&v)
+    fn len(&self) -> u64 {
+        self.0.iter().map(|(lo, hi)| (hi - lo) as u64).sum()
+    }
 }
 
-fn spans_merged(spans: &[(i32, i32)]) -> impl Iterator<Item = (i32, i32)> {
-    let mut m = HashMap::new();
-    for (lo, hi) in spans {
-        m.entry(*lo).and_modify(|v| *v += 1).or_insert(1);
-        m.entry(*hi + 1).and_modify(|v| *v -= 1).or_insert(-1);
+// Rectangle of cells, bounds are inclusive.
+#[derive(Debug, Clone)]
+struct Rect {
+    min: (Coord, Coord),
+    max: (Coord, Coord),
+}
+
+impl Rect {
+    fn area(&self) -> u64 {
+        let w = max(0, self.max.0 - self.min.0 + 1) as u64;
+        let h = max(0, self.max.1 - self.min.1 + 1) as u64;
+        w * h
     }
-    let mut lvl = 0;
-    let mut acc = 0;
-    m.into_iter()
-        .sorted()
-        .filter_map(move |(x, l)| {
-            let nlvl = lvl + l;
-            let end = (lvl == 0) != (nlvl == 0);
-            lvl = nlvl;
-            end.then_some(x)
-        })
-        .enumerate()
-        .filter_map(move |(i, x)| {
-            if i % 2 == 0 {
-                acc = x;
-                None
-            } else {
-                Some((acc, x))
+}
+
+// Union of sensor ranges in rotated coordinates u = x + y and v = x - y,
+// where the diamond of each sensor becomes a square.
+// Cells (x, y) map to points (u, v) with u and v of the same parity.
+struct Coverage {
+    squares: Vec<UvBox>,
+}
+
+// Half-open box [u0, u1) x [v0, v1) in rotated coordinates.
+#[derive(Debug, Clone, Copy)]
+struct UvBox {
+    u: (Coord, Coord),
+    v: (Coord, Coord),
+}
+
+impl Coverage {
+    fn from(rdg: &[Sensor]) -> Coverage {
+        let squares = rdg
+            .iter()
+            .map(|s| {
+                let (u, v) = (s.p.0 + s.p.1, s.p.0 - s.p.1);
+                UvBox {
+                    u: (u - s.range, u + s.range + 1),
+                    v: (v - s.range, v + s.range + 1),
+                }
+            })
+            .collect();
+        Coverage { squares }
+    }
+
+    // Number of cells within range of any sensor.
+    fn area(&self) -> u64 {
+        if self.squares.is_empty() {
+            return 0;
+        }
+        let mut r = Rect {
+            min: (Coord::MAX, Coord::MAX),
+            max: (Coord::MIN, Coord::MIN),
+        };
+        for b in &self.squares {
+            // corners of the diamond
+            r.min.0 = min(r.min.0, (b.u.0 + b.v.0) / 2);
+            r.max.0 = max(r.max.0, (b.u.1 + b.v.1 - 2) / 2);
+            r.min.1 = min(r.min.1, (b.u.0 - b.v.1 + 1) / 2);
+            r.max.1 = max(r.max.1, (b.u.1 - 1 - b.v.0) / 2);
+        }
+        r.area() - self.uncovered_in(&r)
+    }
+
+    // Number of cells in rect out of range of every sensor.
+    fn uncovered_in(&self, rect: &Rect) -> u64 {
+        self.uncovered(rect).iter().map(|b| b.count_in(rect)).sum()
+    }
+
+    // All cells in rect out of range of every sensor.
+    fn gaps(&self, rect: &Rect) -> impl Iterator<Item = (Coord, Coord)> {
+        let boxes = self.uncovered(rect);
+        let rect = rect.clone();
+        boxes.into_iter().flat_map(move |b| b.points_in(&rect))
+    }
+
+    // Sweeps the part of the uv plane containing rect along u, in segments
+    // between the edges of the squares, merging the v ranges of the squares
+    // over each segment. Returns the boxes no square covers.
+    fn uncovered(&self, rect: &Rect) -> Vec<UvBox> {
+        let bounds = rect.uv_bounds();
+        if bounds.u.0 >= bounds.u.1 || bounds.v.0 >= bounds.v.1 {
+            return Vec::new();
+        }
+        let mut us: Vec<Coord> = self
+            .squares
+            .iter()
+            .flat_map(|b| [b.u.0, b.u.1])
+            .filter(|u| bounds.u.0 < *u && *u < bounds.u.1)
+            .chain([bounds.u.0, bounds.u.1])
+            .collect();
+        us.sort();
+        us.dedup();
+        let mut squares = self.squares.clone();
+        squares.sort_by_key(|b| b.u.0);
+
+        let mut r = Vec::new();
+        let mut next = 0;
+        let mut active: Vec<UvBox> = Vec::new();
+        for w in us.windows(2) {
+            active.retain(|b| b.u.1 > w[0]);
+            while next < squares.len() && squares[next].u.0 <= w[0] {
+                if squares[next].u.1 > w[0] {
+                    active.push(squares[next]);
+                }
+                next += 1;
             }
-        })
+            let mut covered = IntervalSet::default();
+            for b in &active {
+                covered.insert(max(b.v.0, bounds.v.0), min(b.v.1, bounds.v.1));
+            }
+            let mut v = bounds.v.0;
+            for &(lo, hi) in covered.0.iter().chain([&(bounds.v.1, bounds.v.1)]) {
+                if v < lo {
+                    r.push(UvBox {
+                        u: (w[0], w[1]),
+                        v: (v, lo),
+                    });
+                }
+                v = hi;
+            }
+        }
+        r
+    }
+}
+
+impl Rect {
+    // Smallest box in rotated coordinates that contains rect.
+    fn uv_bounds(&self) -> UvBox {
+        UvBox {
+            u: (self.min.0 + self.min.1, self.max.0 + self.max.1 + 1),
+            v: (self.min.0 - self.max.1, self.max.0 - self.min.1 + 1),
+        }
+    }
+}
+
+// Bound a + s * u of v for a given u.
+type LinBound = (Coord, Coord);
+
+impl UvBox {
+    // Splits the u range of self within rect into segments [s, e)
+    // where the bounds of v are linear, and returns them
+    // with the lower and upper bound of v in each.
+    fn segments(&self, rect: &Rect) -> Vec<(Coord, Coord, LinBound, LinBound)> {
+        let (x0, y0) = rect.min;
+        let (x1, y1) = rect.max;
+        let lower = [(self.v.0, 0), (2 * x0, -1), (-2 * y1, 1)];
+        let upper = [(self.v.1 - 1, 0), (2 * x1, -1), (-2 * y0, 1)];
+
+        let r = rect.uv_bounds();
+        let (u0, u1) = (max(self.u.0, r.u.0), min(self.u.1, r.u.1));
+        if u0 >= u1 {
+            return Vec::new();
+        }
+        let mut breaks = vec![u0, u1];
+        for t in [&lower, &upper] {
+            for (i, a) in t.iter().enumerate() {
+                for b in &t[i + 1..] {
+                    // a.0 + a.1 * u == b.0 + b.1 * u
+                    if a.1 != b.1 {
+                        let u = (b.0 - a.0).div_euclid(a.1 - b.1);
+                        breaks.extend([u, u + 1].iter().filter(|&&u| u0 < u && u < u1));
+                    }
+                }
+            }
+        }
+        breaks.sort();
+        breaks.dedup();
+
+        breaks
+            .windows(2)
+            .map(|w| {
+                // pick the bounds at the end, where they are no longer ambiguous
+                let at_end = |t: &&LinBound| t.0 + t.1 * (w[1] - 1);
+                let lo = *lower.iter().max_by_key(at_end).unwrap();
+                let hi = *upper.iter().min_by_key(at_end).unwrap();
+                (w[0], w[1], lo, hi)
+            })
+            .collect()
+    }
+
+    // Number of cells in rect within self.
+    fn count_in(&self, rect: &Rect) -> u64 {
+        let mut total = 0;
+        for (s, e, (al, sl), (au, su)) in self.segments(rect) {
+            for p in 0..2 {
+                // u = 2k + p and v = 2j + p, count j for each k
+                let k0 = ceil_div(s - p, 2);
+                let k1 = (e - 1 - p).div_euclid(2);
+                let c0 = (au + su * p - p).div_euclid(2) - ceil_div(al + sl * p - p, 2) + 1;
+                total += sum_positive(c0, su - sl, k0, k1);
+            }
+        }
+        total
+    }
+
+    // Cells in rect within self.
+    fn points_in(&self, rect: &Rect) -> impl Iterator<Item = (Coord, Coord)> {
+        self.segments(rect)
+            .into_iter()
+            .filter_map(|(s, e, (al, sl), (au, su))| {
+                // skip the part of the segment where hi < lo
+                let (c0, c1) = (au - al, su - sl);
+                let (s, e) = match c1.cmp(&0) {
+                    Ordering::Greater => (max(s, ceil_div(-c0, c1)), e),
+                    Ordering::Less => (s, min(e, c0.div_euclid(-c1) + 1)),
+                    Ordering::Equal => (s, if c0 < 0 { s } else { e }),
+                };
+                (s < e).then_some((s..e, (al, sl), (au, su)))
+            })
+            .flat_map(|(us, (al, sl), (au, su))| {
+                us.flat_map(move |u| {
+                    let (lo, hi) = (al + sl * u, au + su * u);
+                    // v must have the parity of u
+                    let lo = lo + (lo - u).rem_euclid(2);
+                    (lo..=hi)
+                        .step_by(2)
+                        .map(move |v| ((u + v) / 2, (u - v) / 2))
+                })
+            })
+    }
+}
+
+fn ceil_div(a: Coord, b: Coord) -> Coord {
+    -(-a).div_euclid(b)
+}
+
+// Sum of max(0, c0 + c1 * k) for k in k0..=k1.
+fn sum_positive(c0: Coord, c1: Coord, k0: Coord, k1: Coord) -> u64 {
+    let (k0, k1) = match c1.cmp(&0) {
+        Ordering::Greater => (max(k0, ceil_div(1 - c0, c1)), k1),
+        Ordering::Less => (k0, min(k1, (c0 - 1).div_euclid(-c1))),
+        Ordering::Equal => {
+            if c0 <= 0 {
+                return 0;
+            }
+            (k0, k1)
+        }
+    };
+    if k0 > k1 {
+        return 0;
+    }
+    let n = k1 - k0 + 1;
+    let (first, last) = (c0 + c1 * k0, c0 + c1 * k1);
+    ((first + last) as i128 * n as i128 / 2) as u64
 }
 
 //Sensor at x=1054910, y=811769: closest beacon is at x=2348729, y=1239977
@@ -79,8 +336,9 @@ fn parse(input: &str) -> Vec<Sensor> {
 
 #[derive(Debug)]
 struct Sensor {
-    p: (i32, i32),
-    range: i32,
+    p: (Coord, Coord),
+    b: (Coord, Coord),
+    range: Coord,
 }
 
 impl Sensor {
@@ -88,34 +346,19 @@ impl Sensor {
         let mut it = line
             .split(' ')
             .filter_map(|s| s.strip_prefix("x=").or_else(|| s.strip_prefix("y=")))
-            .map(|s| s.trim_end_matches(|c| c == ',' || c == ':'))
-            .filter_map(|s| s.parse::<i32>().ok());
+            .map(|s| s.trim_end_matches([',', ':']))
+            .filter_map(|s| s.parse::<Coord>().ok());
         let s = (it.next()?, it.next()?);
         let b = (it.next()?, it.next()?);
         Some(Sensor {
             p: s,
+            b,
             range: manhattan(s, b),
         })
     }
-
-    fn is_seen(&self, b: (i32, i32)) -> bool {
-        manhattan(self.p, b) <= self.range
-    }
-
-    /// Return an iterator over the positions just outside the sensor range.
-    fn boundary(&self) -> impl Iterator<Item = (i32, i32)> {
-        let (x, y) = self.p;
-        let r = self.range + 1;
-
-        let a = (0..r).map(move |i| (x - (r - i), y - i));
-        let b = (0..r).map(move |i| (x + (r - i), y + i));
-        let c = (0..r).map(move |i| (x - i, y + (r - i)));
-        let d = (0..r).map(move |i| (x + i, y - (r - i)));
-        a.chain(b).chain(c).chain(d)
-    }
 }
 
-fn manhattan(p: (i32, i32), q: (i32, i32)) -> i32 {
+fn manhattan(p: (Coord, Coord), q: (Coord, Coord)) -> Coord {
     (p.0 - q.0).abs() + (p.1 - q.1).abs()
 }
 
@@ -131,6 +374,18 @@ mod test {
 
        ...aaaaaaaaaaaaaaaaaaaaaaaaaaa.
     */
+    fn brute_force(rdg: &[Sensor], rect: &Rect) -> Vec<(Coord, Coord)> {
+        let mut v = Vec::new();
+        for x in rect.min.0..=rect.max.0 {
+            for y in rect.min.1..=rect.max.1 {
+                if rdg.iter().all(|s| manhattan(s.p, (x, y)) > s.range) {
+                    v.push((x, y));
+                }
+            }
+        }
+        v
+    }
+
     #[test]
     fn day15_works() {
         let sample = "\
@@ -151,6 +406,50 @@ Sensor at x=20, y=1: closest beacon is at x=15, y=3
 ";
         let rdg = parse(sample);
         assert_eq!(count_no_beacon(&rdg, 10), 26);
-        assert_eq!(scan_beacon(&rdg, 20), Some(56000011));
+
+        let cov = Coverage::from(&rdg);
+        let area = Rect {
+            min: (0, 0),
+            max: (20, 20),
+        };
+        assert_eq!(scan_beacon(&cov, &area).ok(), Some(56000011));
+
+        for rect in [
+            area,
+            Rect {
+                min: (-13, 5),
+                max: (30, 31),
+            },
+            Rect {
+                min: (3, -2),
+                max: (3, 40),
+            },
+            Rect {
+                min: (-20, -25),
+                max: (45, 50),
+            },
+        ] {
+            let mut gaps: Vec<_> = cov.gaps(&rect).collect();
+            gaps.sort();
+            let brute = brute_force(&rdg, &rect);
+            assert_eq!(gaps, brute);
+            assert_eq!(cov.uncovered_in(&rect), brute.len() as u64);
+        }
+
+        let all = Rect {
+            min: (-20, -25),
+            max: (45, 50),
+        };
+        assert_eq!(
+            cov.area(),
+            all.area() - brute_force(&rdg, &all).len() as u64
+        );
+
+        let mut s = IntervalSet::default();
+        for (lo, hi) in [(5, 8), (0, 2), (2, 3), (10, 12), (7, 11), (20, 20)] {
+            s.insert(lo, hi);
+        }
+        assert_eq!(s.0, vec![(0, 3), (5, 12)]);
+        assert_eq!(s.len(), 10);
     }
 }
@@ -1,10 +1,15 @@
+use crate::param::Param;
 use anyhow::{anyhow, bail, Result};
-use pathfinding::prelude::bfs_reach;
-use std::collections::HashMap;
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+
+const TIME1: Param<i32> = Param::new("day16.time1", 30);
+const TIME2: Param<i32> = Param::new("day16.time2", 26);
+const AGENTS2: Param<usize> = Param::new("day16.agents2", 2);
 
 pub fn run(input: &str) -> Result<String> {
     let rdg = parse(input)?;
-    let working = working_valves(&rdg);
+    let working = working_valves(&rdg, &[START])?;
     let verbose = crate::verbose();
     if verbose {
         for v in &working {
@@ -12,7 +17,7 @@ pub fn run(input: &str) -> Result<String> {
             for (i, (j, d)) in v.next.iter().enumerate() {
                 print!(
                     "{}{}:{}",
-                    (i != 0).then_some(", ").unwrap_or_default(),
+                    if i != 0 { ", " } else { "" },
                     working[*j].label,
                     d
                 );
@@ -20,128 +25,252 @@ pub fn run(input: &str) -> Result<String> {
             println!();
         }
     }
-    let p1 = pressure_release_1(&working, verbose)?;
-    let p2 = pressure_release_2(&working, verbose)?;
+    let p1 = pressure_release_1(&working, TIME1.get()?, verbose)?;
+    let p2 = pressure_release_2(&working, TIME2.get()?, verbose)?;
     Ok(format!("{} {}", p1, p2))
 }
 
-fn pressure_release_1(wv: &[WorkValve], _verbose: bool) -> Result<i32> {
-    const TIME: i32 = 30;
-    pressure_release_impl(wv, TIME, 0)
-        .map(|n| n.released)
-        .max()
-        .ok_or_else(|| anyhow!("pressure release failed"))
+fn pressure_release_1(wv: &[WorkValve], time: i32, _verbose: bool) -> Result<i32> {
+    let agents = [Agent::new(wv, START, time)?];
+    Ok(pressure_release(wv, &agents)?.released)
 }
 
-fn pressure_release_2(wv: &[WorkValve], verbose: bool) -> Result<i32> {
-    const TIME: i32 = 26;
+fn pressure_release_2(wv: &[WorkValve], time: i32, verbose: bool) -> Result<i32> {
     let max_rate: i32 = wv.iter().map(|v| v.rate).sum();
     if verbose {
         println!("max. rate: {}", max_rate);
     }
-    let mut fst = pressure_release_impl(wv, TIME, 0)
-        .map(|n| (n.open, n.released))
-        .collect::<Vec<_>>();
-    fst.sort_by_key(|x| std::cmp::Reverse(x.1));
+    let agents = (0..AGENTS2.get()?)
+        .map(|_| Agent::new(wv, START, time))
+        .collect::<Result<Vec<_>>>()?;
+    let plan = pressure_release(wv, &agents)?;
     if verbose {
-        println!("result size: {}", fst.len());
+        print!("{}", plan);
+    }
+    Ok(plan.released)
+}
+
+const START: &str = "AA";
+
+// An agent opening valves, starting at valve start with time minutes to go.
+// All agents stop when the volcano erupts, so an agent with less time
+// starts later than the others.
+#[derive(Debug, Clone, Copy)]
+struct Agent {
+    start: usize,
+    time: i32,
+}
+
+impl Agent {
+    fn new(wv: &[WorkValve], start: &str, time: i32) -> Result<Agent> {
+        let start = wv
+            .iter()
+            .position(|v| v.label == start)
+            .ok_or_else(|| anyhow!("{} is not among the working and start valves", start))?;
+        Ok(Agent { start, time })
     }
+}
+
+#[derive(Debug)]
+struct Plan {
+    released: i32,
+    schedule: Vec<Opening>, // ordered by minute
+}
+
+#[derive(Debug, PartialEq)]
+struct Opening {
+    minute: i32, // counted from when the first agent starts
+    agent: usize,
+    valve: String,
+}
 
-    let mut bestr = 0;
-    for (i, x) in fst.iter().enumerate() {
-        let (xo, xr) = *x;
-        if xr < bestr / 2 {
-            break;
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for o in &self.schedule {
+            writeln!(
+                f,
+                "== Minute {} == agent {} opens {}",
+                o.minute, o.agent, o.valve
+            )?;
         }
+        writeln!(f, "released: {}", self.released)
+    }
+}
 
-        for y in fst.iter().skip(i + 1) {
-            let (yo, yr) = *y;
-            let r = xr + yr;
-            if r <= bestr {
+// Finds the best total release of agents opening disjoint sets of valves.
+fn pressure_release(wv: &[WorkValve], agents: &[Agent]) -> Result<Plan> {
+    if agents.is_empty() {
+        bail!("no agents");
+    }
+    let reach: Vec<Reach> = agents
+        .iter()
+        .map(|a| Reach::new(wv, a.start, a.time))
+        .collect();
+
+    // best combinations of the agents so far: release, union of valves opened
+    // and the chosen step of each agent
+    let mut front = vec![(0, ValveSet::new(wv.len()), Vec::new())];
+    for (k, r) in reach.iter().enumerate() {
+        let mut sets: Vec<_> = r.best.iter().collect();
+        sets.sort_by_key(|(_, (x, _))| std::cmp::Reverse(*x));
+        let top = sets.first().map_or(0, |(_, (x, _))| *x);
+        let last = k + 1 == reach.len();
+
+        front.sort_by_key(|(x, _, _)| std::cmp::Reverse(*x));
+        let mut next: HashMap<ValveSet, (i32, Vec<usize>)> = HashMap::new();
+        let mut bestr = -1;
+        for (fr, fo, fsteps) in &front {
+            if last && fr + top <= bestr {
                 break;
             }
-
-            if (xo & yo) == 0 {
-                bestr = r;
+            for (o, (r, step)) in &sets {
+                let r = fr + r;
+                if last && r <= bestr {
+                    break;
+                }
+                if !fo.is_disjoint(o) {
+                    continue;
+                }
+                let u = fo.union(o);
+                if next.get(&u).is_none_or(|(x, _)| *x < r) {
+                    let mut v = fsteps.clone();
+                    v.push(*step);
+                    next.insert(u, (r, v));
+                }
+                if last {
+                    // sets are sorted, so this is the best for fo
+                    bestr = r;
+                    break;
+                }
             }
         }
-    }
-    if bestr == 0 {
-        bail!("pressure release failed");
+        front = next.into_iter().map(|(o, (r, v))| (r, o, v)).collect();
     }
 
-    Ok(bestr)
-}
-
-fn pressure_release_impl(
-    wv: &[WorkValve],
-    time: i32,
-    start_open: u32,
-) -> impl Iterator<Item = Node> + '_ {
-    bfs_reach(Node::new(time, start_open), move |&from| {
-        let node = &wv[from.index];
-        node.next
-            .iter()
-            .filter_map(move |&(i, dist)| {
-                (!from.is_open(i)).then_some(from.goto(i, dist, wv[i].rate))
+    let (released, _, steps) = front
+        .into_iter()
+        .max_by_key(|(r, _, _)| *r)
+        .ok_or_else(|| anyhow!("pressure release failed"))?;
+    let time = agents.iter().map(|a| a.time).max().unwrap_or(0);
+    let mut schedule: Vec<Opening> = steps
+        .iter()
+        .enumerate()
+        .flat_map(|(agent, &step)| {
+            reach[agent].path(step).map(move |(valve, ttg)| Opening {
+                minute: time - ttg,
+                agent,
+                valve: wv[valve].label.clone(),
             })
-            .filter(|next| next.ttg > 0)
-    })
+        })
+        .collect();
+    schedule.sort_by_key(|o| (o.minute, o.agent));
+    Ok(Plan { released, schedule })
 }
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
-struct Node {
-    index: usize,
-    ttg: i32,  // minutes remaining
-    open: u32, // valves opened so far
+// Best release for every set of valves a single agent can open.
+struct Reach {
+    best: HashMap<ValveSet, (i32, usize)>, // release and last step
+    steps: Vec<Step>,
+}
 
-    rate: i32,     // current release rate
-    released: i32, // pressure released so far
+struct Step {
+    valve: usize,
+    ttg: i32, // minutes remaining after opening valve
+    prev: Option<usize>,
 }
 
-impl Node {
-    fn new(ttg: i32, open: u32) -> Node {
-        Node {
-            index: 0,
-            ttg,
-            open,
-            rate: 0,
-            released: 0,
+impl Reach {
+    // Explores opening valves within time, starting at valve start.
+    // States are (position, time, open set), and only the best release
+    // is kept for each of them.
+    fn new(wv: &[WorkValve], start: usize, time: i32) -> Reach {
+        let mut best = HashMap::new();
+        let mut seen = HashMap::new();
+        let mut steps = vec![Step {
+            valve: start,
+            ttg: time,
+            prev: None,
+        }];
+        let mut stack = vec![(0, ValveSet::new(wv.len()), 0)];
+        while let Some((id, open, released)) = stack.pop() {
+            let (index, ttg) = (steps[id].valve, steps[id].ttg);
+            let b = best.entry(open.clone()).or_insert((released, id));
+            if b.0 < released {
+                *b = (released, id);
+            }
+
+            for &(i, dist) in &wv[index].next {
+                let ttg = ttg - dist - 1; // time to move and open valve
+                if ttg <= 0 || open.contains(i) {
+                    continue;
+                }
+                let open = open.with(i);
+                let released = released + ttg * wv[i].rate;
+                match seen.entry((i, ttg, open.clone())) {
+                    Entry::Occupied(e) if *e.get() >= released => continue,
+                    Entry::Occupied(mut e) => {
+                        e.insert(released);
+                    }
+                    Entry::Vacant(e) => {
+                        e.insert(released);
+                    }
+                }
+                steps.push(Step {
+                    valve: i,
+                    ttg,
+                    prev: Some(id),
+                });
+                stack.push((steps.len() - 1, open, released));
+            }
         }
+        Reach { best, steps }
     }
 
-    fn goto(&self, index: usize, dist: i32, rate: i32) -> Node {
-        let time = dist + 1; // time to move and open valve
-        Node {
-            index,
-            ttg: self.ttg - time,
-            open: self.open | (1 << index),
-            rate: self.rate + rate,
-            released: self.released + (self.ttg - time) * rate,
+    // Valves opened and the minutes remaining after each, in order.
+    fn path(&self, step: usize) -> impl Iterator<Item = (usize, i32)> {
+        let mut v = Vec::new();
+        let mut id = Some(step);
+        while let Some(i) = id {
+            let s = &self.steps[i];
+            if s.prev.is_some() {
+                v.push((s.valve, s.ttg));
+            }
+            id = s.prev;
         }
+        v.into_iter().rev()
     }
+}
+
+// Set of working valves, indexed like the working valve list.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+struct ValveSet(Vec<u64>);
 
-    fn is_open(&self, index: usize) -> bool {
-        (self.open & (1 << index)) != 0
+impl ValveSet {
+    fn new(n: usize) -> ValveSet {
+        ValveSet(vec![0; n.div_ceil(64)])
     }
-}
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
-struct Elef {
-    a: Agent,
-    b: Agent,
+    fn contains(&self, index: usize) -> bool {
+        (self.0[index / 64] & (1 << (index % 64))) != 0
+    }
 
-    time: i32, // minutes elapsed
-    open: u64, // valves opened so far
+    fn with(&self, index: usize) -> ValveSet {
+        let mut r = self.clone();
+        r.0[index / 64] |= 1 << (index % 64);
+        r
+    }
 
-    rate: i32,     // current release rate
-    released: i32, // pressure released so far
-}
+    fn union(&self, other: &ValveSet) -> ValveSet {
+        ValveSet(
+            std::iter::zip(&self.0, &other.0)
+                .map(|(a, b)| a | b)
+                .collect(),
+        )
+    }
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
-struct Agent {
-    loc: usize,
-    ttg: i32, // time to go
+    fn is_disjoint(&self, other: &ValveSet) -> bool {
+        std::iter::zip(&self.0, &other.0).all(|(a, b)| (a & b) == 0)
+    }
 }
 
 // a working valve
@@ -151,20 +280,27 @@ struct WorkValve {
     next: Vec<(usize, i32)>, // index and dist. of rooms with wotking valves
 }
 
-fn working_valves(rdg: &[Valve]) -> Vec<WorkValve> {
+// The valves with a rate and the start valves, with the distances
+// from each of them to the valves with a rate.
+fn working_valves(rdg: &[Valve], starts: &[&str]) -> Result<Vec<WorkValve>> {
+    if let Some(s) = starts.iter().find(|s| !rdg.iter().any(|v| v.label == **s)) {
+        bail!("no start valve {}", s);
+    }
+    let keep = |v: &Valve| v.rate != 0 || starts.contains(&v.label.as_str());
     let nsteps = &map_nsteps(rdg);
 
     let mut vidx = Vec::new();
-    vidx.resize(rdg.len(), usize::max_value());
+    vidx.resize(rdg.len(), usize::MAX);
     rdg.iter()
         .enumerate()
-        .filter_map(|(i, v)| (i == 0 || v.rate != 0).then_some(i))
+        .filter_map(|(i, v)| keep(v).then_some(i))
         .enumerate()
         .for_each(|(new_idx, i)| vidx[i] = new_idx);
 
-    rdg.iter()
+    let wv = rdg
+        .iter()
         .enumerate()
-        .filter(|(i, v)| i == &0 || v.rate != 0)
+        .filter(|(_, v)| keep(v))
         .map(|(i, v)| {
             let mut next: Vec<_> = nsteps[i]
                 .iter()
@@ -180,7 +316,8 @@ fn working_valves(rdg: &[Valve]) -> Vec<WorkValve> {
                 next,
             }
         })
-        .collect()
+        .collect();
+    Ok(wv)
 }
 
 fn map_nsteps(rdg: &[Valve]) -> Vec<Vec<i32>> {
@@ -189,7 +326,7 @@ fn map_nsteps(rdg: &[Valve]) -> Vec<Vec<i32>> {
 
 fn calc_nsteps(rdg: &[Valve], i: usize) -> Vec<i32> {
     let mut v = Vec::new();
-    v.resize(rdg.len(), i32::max_value());
+    v.resize(rdg.len(), i32::MAX);
     v[i] = 0;
     let mut acc = vec![i];
     let mut dist = 1;
@@ -198,7 +335,7 @@ fn calc_nsteps(rdg: &[Valve], i: usize) -> Vec<i32> {
         acc = Vec::new();
         for i in w {
             for &j in &rdg[i].next {
-                if v[j] == i32::max_value() {
+                if v[j] == i32::MAX {
                     v[j] = dist;
                     acc.push(j);
                 }
@@ -300,9 +437,73 @@ Valve JJ has flow rate=21; tunnel leads to valve II
             println!();
         }
 
-        let working = working_valves(&rdg);
+        let working = working_valves(&rdg, &["AA", "II"]).unwrap();
+
+        assert_eq!(pressure_release_1(&working, 30, true).ok(), Some(1651));
+        assert_eq!(pressure_release_2(&working, 26, true).ok(), Some(1707));
 
-        assert_eq!(pressure_release_1(&working, true).ok(), Some(1651));
-        assert_eq!(pressure_release_2(&working, true).ok(), Some(1707));
+        let agents = [
+            Agent::new(&working, "AA", 26).unwrap(),
+            Agent::new(&working, "II", 10).unwrap(),
+        ];
+        let plan = pressure_release(&working, &agents).unwrap();
+        let total: i32 = plan
+            .schedule
+            .iter()
+            .map(|o| {
+                let v = working.iter().find(|v| v.label == o.valve).unwrap();
+                (26 - o.minute) * v.rate
+            })
+            .sum();
+        assert_eq!(total, plan.released);
+        assert_eq!(plan.schedule.len(), 6);
+        // the agent at II starts 16 minutes late
+        let late: Vec<_> = plan.schedule.iter().filter(|o| o.agent == 1).collect();
+        assert!(!late.is_empty() && late.iter().all(|o| o.minute > 16));
+        assert!(Agent::new(&working, "FF", 10).is_err());
+        assert!(working_valves(&rdg, &["ZZ"]).is_err());
+
+        let plan = pressure_release(&working, &agents[..1]).unwrap();
+        assert_eq!(
+            plan.schedule.first(),
+            Some(&Opening {
+                minute: 2,
+                agent: 0,
+                valve: "DD".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn many_valves() {
+        // AA is connected to 70 valves with rates 1..=70
+        let name = |i: usize| {
+            format!(
+                "{}{}",
+                (b'B' + (i / 26) as u8) as char,
+                (b'A' + (i % 26) as u8) as char
+            )
+        };
+        let mut input = format!(
+            "Valve AA has flow rate=0; tunnels lead to valves {}\n",
+            (0..70).map(name).collect::<Vec<_>>().join(", ")
+        );
+        for i in 0..70 {
+            input += &format!(
+                "Valve {} has flow rate={}; tunnel leads to valve AA\n",
+                name(i),
+                i + 1
+            );
+        }
+        let working = working_valves(&parse(&input).unwrap(), &[START]).unwrap();
+        assert_eq!(working.len(), 71);
+        assert_eq!(
+            pressure_release_1(&working, 8, false).ok(),
+            Some(6 * 70 + 3 * 69)
+        );
+        assert_eq!(
+            pressure_release_2(&working, 8, false).ok(),
+            Some(6 * (70 + 69) + 3 * (68 + 67))
+        );
     }
 }
@@ -1,9 +1,13 @@
+use crate::param::Param;
 use anyhow::Result;
 
+const ROCKS1: Param<usize> = Param::new("day17.rocks1", 2022);
+const ROCKS2: Param<usize> = Param::new("day17.rocks2", 1000000000000);
+
 pub fn run(input: &str) -> Result<String> {
-    let p1 = tower_height(input, 2022);
+    let p1 = tower_height(input, ROCKS1.get()?);
     // 1566227410342 too low
-    let p2 = tower_height(input, 1000000000000);
+    let p2 = tower_height(input, ROCKS2.get()?);
     Ok(format!("{} {}", p1, p2))
 }
 
@@ -1,17 +1,25 @@
+use crate::param::Param;
 use std::cmp::max;
-use std::collections::{HashSet, VecDeque};
+use std::fmt;
+
+const TIME1: Param<usize> = Param::new("day19.time1", 24);
+const TIME2: Param<usize> = Param::new("day19.time2", 32);
+const NBP2: Param<usize> = Param::new("day19.blueprints2", 3);
+const EXPLAIN: Param<usize> = Param::new("day19.explain", 0);
 
 pub fn run(input: &str) -> anyhow::Result<String> {
     let bps = &parse(input);
     let verbose = crate::verbose();
+    // number of the blueprint whose build plan to print, 0 for none
+    let explain = EXPLAIN.get()?;
     if verbose {
         println!("Problem 1");
     }
-    let p1 = sim1(bps);
+    let p1 = sim1(bps, TIME1.get()?, explain)?;
     if verbose {
         println!("Problem 2");
     }
-    let p2 = sim2(bps);
+    let p2 = sim2(bps, TIME2.get()?, NBP2.get()?, explain)?;
     Ok(format!("{} {}", p1, p2))
 }
 
@@ -19,49 +27,277 @@ fn parse(input: &str) -> Vec<Blueprint> {
     input.lines().filter_map(Blueprint::parse).collect()
 }
 
-fn sim1(bps: &[Blueprint]) -> usize {
-    bps.iter()
-        .map(|bp| (bp.num as usize) * bfs_sim(bp, 24))
-        .sum()
+fn sim1(bps: &[Blueprint], time: usize, explain: usize) -> anyhow::Result<usize> {
+    Ok(solve_all(bps, time, explain)?
+        .iter()
+        .map(|(bp, best)| (bp.num as usize) * best.geodes)
+        .sum())
+}
+
+fn sim2(bps: &[Blueprint], time: usize, nbp: usize, explain: usize) -> anyhow::Result<usize> {
+    let n = nbp.min(bps.len());
+    Ok(solve_all(&bps[..n], time, explain)?
+        .iter()
+        .map(|(_, best)| best.geodes)
+        .product())
+}
+
+// Solves the blueprints in parallel, and prints the plan
+// of the blueprint numbered explain.
+fn solve_all(
+    bps: &[Blueprint],
+    time: usize,
+    explain: usize,
+) -> anyhow::Result<Vec<(&Blueprint, Best)>> {
+    let r: Vec<_> = std::thread::scope(|sc| {
+        let handles: Vec<_> = bps
+            .iter()
+            .map(|bp| sc.spawn(move || (bp, max_geodes(bp, time))))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    if crate::verbose() {
+        for (bp, best) in &r {
+            let msg;
+            println!(
+                "  Blueprint {:2}: {}",
+                bp.num,
+                match best.geodes {
+                    0 => "no geode",
+                    1 => " 1 geode",
+                    _ => {
+                        msg = format!("{:2} geodes", best.geodes);
+                        msg.as_str()
+                    }
+                }
+            );
+        }
+    }
+    for (bp, best) in &r {
+        if explain != 0 && bp.num as usize == explain {
+            println!("Blueprint {} in {} minutes:", bp.num, time);
+            print!("{}", best.plan(bp, time));
+        }
+    }
+
+    Ok(r)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Robot {
+    Ore,
+    Clay,
+    Obsidian,
+    Geode,
 }
 
-fn sim2(bps: &[Blueprint]) -> usize {
-    bps.iter().take(3).map(|bp| bfs_sim(bp, 32)).product()
+// A robot and the minute its construction starts.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Build {
+    robot: Robot,
+    minute: usize,
 }
 
-fn bfs_sim(bp: &Blueprint, time: usize) -> usize {
-    let mut seen = HashSet::new();
-    let mut queue = VecDeque::from([State::new(time)]);
-    let mut best = 0;
-    while let Some(s) = queue.pop_front() {
-        best = max(best, s.score());
-        if s.ttg <= 1 {
-            continue;
+impl Robot {
+    fn name(&self) -> &'static str {
+        match self {
+            Robot::Ore => "ore-collecting",
+            Robot::Clay => "clay-collecting",
+            Robot::Obsidian => "obsidian-collecting",
+            Robot::Geode => "geode-cracking",
         }
-        for r in s.nexts2(bp) {
-            if seen.insert(r) {
-                queue.push_back(r);
+    }
+
+    fn cost(&self, bp: &Blueprint) -> Counts {
+        let (ore, cly, obs) = match self {
+            Robot::Ore => (bp.ore_ore, 0, 0),
+            Robot::Clay => (bp.cly_ore, 0, 0),
+            Robot::Obsidian => (bp.obs_ore, bp.obs_cly, 0),
+            Robot::Geode => (bp.gde_ore, 0, bp.gde_obs),
+        };
+        Counts {
+            ore,
+            cly,
+            obs,
+            gde: 0,
+        }
+    }
+
+    fn add_to(&self, c: &mut Counts) {
+        match self {
+            Robot::Ore => c.ore += 1,
+            Robot::Clay => c.cly += 1,
+            Robot::Obsidian => c.obs += 1,
+            Robot::Geode => c.gde += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Best {
+    geodes: usize,
+    builds: Vec<Build>,
+}
+
+impl Best {
+    // Replays the build order minute by minute.
+    fn plan(&self, bp: &Blueprint, time: usize) -> Plan {
+        let mut s = State::new(time);
+        let mut builds = self.builds.iter().peekable();
+        let mut minutes = Vec::with_capacity(time);
+        for minute in 1..=time {
+            let build = builds.next_if(|b| b.minute == minute).map(|b| b.robot);
+            let robot = s.robot;
+            if let Some(r) = build {
+                s.res = s.res.sub(&r.cost(bp));
             }
+            s = s.wait(1);
+            if let Some(r) = build {
+                r.add_to(&mut s.robot);
+            }
+            minutes.push(Minute {
+                minute,
+                build,
+                robot,
+                res: s.res,
+            });
         }
+        Plan { bp: *bp, minutes }
     }
+}
 
-    if crate::verbose() {
-        let msg;
-        println!(
-            "  Blueprint {:2}: {}",
-            bp.num,
-            match best {
-                0 => "no geode",
-                1 => " 1 geode",
-                _ => {
-                    msg = format!("{:2} geodes", best);
-                    msg.as_str()
+struct Plan {
+    bp: Blueprint,
+    minutes: Vec<Minute>,
+}
+
+struct Minute {
+    minute: usize,
+    build: Option<Robot>, // robot whose construction starts this minute
+    robot: Counts,        // robots collecting during the minute
+    res: Counts,          // resources at the end of the minute
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const KINDS: [Robot; 4] = [Robot::Ore, Robot::Clay, Robot::Obsidian, Robot::Geode];
+        const NAMES: [&str; 4] = ["ore", "clay", "obsidian", "open geode"];
+        let plural = |n: Count, s: &str| format!("{} {}{}", n, s, if n == 1 { "" } else { "s" });
+
+        for (i, m) in self.minutes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "== Minute {} ==", m.minute)?;
+            if let Some(r) = m.build {
+                let cost = r.cost(&self.bp).to_array();
+                let spent: Vec<_> = cost
+                    .iter()
+                    .zip(NAMES)
+                    .filter(|(&n, _)| n > 0)
+                    .map(|(n, name)| format!("{} {}", n, name))
+                    .collect();
+                let article = if r == Robot::Ore || r == Robot::Obsidian {
+                    "an"
+                } else {
+                    "a"
+                };
+                writeln!(
+                    f,
+                    "Spend {} to start building {} {} robot.",
+                    spent.join(" and "),
+                    article,
+                    r.name()
+                )?;
+            }
+            let (robot, res) = (m.robot.to_array(), m.res.to_array());
+            for (k, kind) in KINDS.iter().enumerate() {
+                let n = robot[k];
+                if n == 0 {
+                    continue;
                 }
+                let (verb, what) = if *kind == Robot::Geode {
+                    (if n == 1 { "cracks" } else { "crack" }, "geode")
+                } else {
+                    (if n == 1 { "collects" } else { "collect" }, NAMES[k])
+                };
+                let collected = if *kind == Robot::Geode {
+                    plural(n, what)
+                } else {
+                    format!("{} {}", n, what)
+                };
+                let total = if *kind == Robot::Geode {
+                    plural(res[k], NAMES[k])
+                } else {
+                    format!("{} {}", res[k], NAMES[k])
+                };
+                writeln!(
+                    f,
+                    "{} {} {}; you now have {}.",
+                    plural(n, &format!("{} robot", kind.name())),
+                    verb,
+                    collected,
+                    total
+                )?;
             }
-        );
+            if let Some(r) = m.build {
+                let mut after = m.robot;
+                r.add_to(&mut after);
+                let k = KINDS.iter().position(|&x| x == r).unwrap();
+                writeln!(
+                    f,
+                    "The new {} robot is ready; you now have {} of them.",
+                    r.name(),
+                    after.to_array()[k]
+                )?;
+            }
+        }
+        Ok(())
     }
+}
 
-    best
+fn max_geodes(bp: &Blueprint, time: usize) -> Best {
+    let mut search = Search {
+        bp,
+        time,
+        path: Vec::new(),
+        best: Best {
+            geodes: 0,
+            builds: Vec::new(),
+        },
+    };
+    search.dfs(State::new(time));
+    search.best
+}
+
+// Depth first branch and bound over the robot to build next.
+struct Search<'a> {
+    bp: &'a Blueprint,
+    time: usize,
+    path: Vec<Build>,
+    best: Best,
+}
+
+impl<'a> Search<'a> {
+    fn dfs(&mut self, s: State) {
+        let score = s.score();
+        if score > self.best.geodes {
+            self.best.geodes = score;
+            self.best.builds = self.path.clone();
+        }
+        if s.ttg <= 1 || s.upper_bound(self.bp) <= self.best.geodes {
+            return;
+        }
+        for (robot, t, next) in s.nexts(self.bp) {
+            self.path.push(Build {
+                robot,
+                minute: self.time - s.ttg + t,
+            });
+            self.dfs(next);
+            self.path.pop();
+        }
+    }
 }
 
 type Count = u8;
@@ -170,17 +406,35 @@ impl State {
         )
     }
 
-    fn nexts2(&self, bp: &Blueprint) -> impl Iterator<Item = State> {
+    // Possible next robots to build, with the minutes to wait for it.
+    // Geode robots come first to find good solutions early.
+    fn nexts(&self, bp: &Blueprint) -> impl Iterator<Item = (Robot, usize, State)> {
+        let t = |s: &State| self.ttg - s.ttg;
         [
-            self.make_ore_robot(bp),
-            self.make_cly_robot(bp),
-            self.make_obs_robot(bp),
-            self.make_gde_robot(bp),
+            self.make_gde_robot(bp).map(|s| (Robot::Geode, t(&s), s)),
+            self.make_obs_robot(bp).map(|s| (Robot::Obsidian, t(&s), s)),
+            self.make_cly_robot(bp).map(|s| (Robot::Clay, t(&s), s)),
+            self.make_ore_robot(bp).map(|s| (Robot::Ore, t(&s), s)),
         ]
         .into_iter()
         .flatten()
     }
 
+    // Optimistic geode count: an obsidian robot is built for free every minute,
+    // and a geode robot too whenever there is obsidian for it.
+    fn upper_bound(&self, bp: &Blueprint) -> usize {
+        let mut score = self.score();
+        let mut obs = self.res.obs as usize;
+        for (i, t) in (0..self.ttg).rev().enumerate() {
+            if obs >= bp.gde_obs as usize {
+                obs -= bp.gde_obs as usize;
+                score += t;
+            }
+            obs += self.robot.obs as usize + i;
+        }
+        score
+    }
+
     fn make_ore_robot(&self, bp: &Blueprint) -> Option<State> {
         if Self::res_max(self.ttg, self.res.ore, self.robot.ore, bp.ore_max) {
             return None;
@@ -298,19 +552,74 @@ struct Counts {
     gde: Count,
 }
 
+impl Counts {
+    fn sub(&self, other: &Counts) -> Counts {
+        Counts {
+            ore: self.ore - other.ore,
+            cly: self.cly - other.cly,
+            obs: self.obs - other.obs,
+            gde: self.gde - other.gde,
+        }
+    }
+
+    fn to_array(self) -> [Count; 4] {
+        [self.ore, self.cly, self.obs, self.gde]
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    const SAMPLE: &str = "\
+Blueprint 1: Each ore robot costs 4 ore. Each clay robot costs 2 ore. Each obsidian robot costs 3 ore and 14 clay. Each geode robot costs 2 ore and 7 obsidian.
+Blueprint 2: Each ore robot costs 2 ore. Each clay robot costs 3 ore. Each obsidian robot costs 3 ore and 8 clay. Each geode robot costs 3 ore and 12 obsidian.
+";
+
     #[test]
-    fn bfs_sim_works() {
-        let sample = "Blueprint 1: Each ore robot costs 4 ore. Each clay robot costs 2 ore. Each obsidian robot costs 3 ore and 14 clay. Each geode robot costs 2 ore and 7 obsidian.\n";
-        assert_eq!(bfs_sim(&Blueprint::parse(sample).unwrap(), 24), 9);
+    fn max_geodes_works() {
+        let bps = parse(SAMPLE);
+        assert_eq!(max_geodes(&bps[0], 24).geodes, 9);
+        assert_eq!(max_geodes(&bps[1], 24).geodes, 12);
+        assert_eq!(sim1(&bps, 24, 0).unwrap(), 33);
+        assert_eq!(sim2(&bps, 32, 3, 0).unwrap(), 56 * 62);
     }
 
     #[test]
-    fn bfs_sim2_works() {
+    fn max_geodes2_works() {
         let sample = "Blueprint 24: Each ore robot costs 4 ore. Each clay robot costs 3 ore. Each obsidian robot costs 3 ore and 7 clay. Each geode robot costs 3 ore and 9 obsidian.\n";
-        assert_eq!(bfs_sim(&Blueprint::parse(sample).unwrap(), 24), 9);
+        assert_eq!(max_geodes(&Blueprint::parse(sample).unwrap(), 24).geodes, 9);
+    }
+
+    #[test]
+    fn build_order() {
+        let bps = parse(SAMPLE);
+        let best = max_geodes(&bps[0], 24);
+        let geodes: usize = best
+            .builds
+            .iter()
+            .filter(|b| b.robot == Robot::Geode)
+            .map(|b| 24 - b.minute)
+            .sum();
+        assert_eq!(geodes, 9);
+        assert!(best.builds.windows(2).all(|w| w[0].minute < w[1].minute));
+    }
+
+    #[test]
+    fn plan_works() {
+        let bps = parse(SAMPLE);
+        let plan = max_geodes(&bps[0], 24).plan(&bps[0], 24);
+        assert_eq!(plan.minutes.len(), 24);
+        assert_eq!(plan.minutes[23].res.gde, 9);
+        let text = plan.to_string();
+        assert!(text.starts_with(
+            "== Minute 1 ==\n1 ore-collecting robot collects 1 ore; you now have 1 ore.\n"
+        ));
+        assert!(text.contains("robots crack"));
+        assert!(text.ends_with("you now have 9 open geodes.\n"));
+        assert_eq!(
+            text.matches("is ready").count(),
+            plan.minutes.iter().filter(|m| m.build.is_some()).count()
+        );
     }
 }
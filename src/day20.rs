@@ -1,163 +1,504 @@
-use anyhow::{anyhow, Result};
+use crate::param::Param;
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::str::FromStr;
+
+const ANCHOR: Param<Num> = Param::new("day20.anchor", 0);
+const OFFSETS: Param<Offsets> =
+    Param::new("day20.offsets", Offsets(Cow::Borrowed(&[1000, 2000, 3000])));
 
 pub fn run(input: &str) -> Result<String> {
-    let p1 = coord_sum(input, 1, 1)?;
-    let p2 = coord_sum(input, DECR_KEY, 10)?;
+    let (anchor, offsets) = (ANCHOR.get()?, OFFSETS.get()?);
+    let p1 = coord_sum(input, 1, 1, anchor, &offsets.0)?;
+    let p2 = coord_sum(input, DECR_KEY, 10, anchor, &offsets.0)?;
     Ok(format!("{} {}", p1, p2))
 }
 
 type Num = i64;
 const DECR_KEY: Num = 811589153;
 
-fn coord_sum(input: &str, key: Num, nmix: usize) -> Result<Num> {
-    let mut m = Mixer::from(input, key)?;
+// Positions after the anchor, e.g. "1000,2000,3000".
+#[derive(Debug, Clone)]
+struct Offsets(Cow<'static, [usize]>);
+
+impl FromStr for Offsets {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Offsets> {
+        let v = s
+            .split(',')
+            .map(|x| {
+                x.trim()
+                    .parse::<usize>()
+                    .map_err(|e| anyhow!("invalid offset {:?}: {}", x, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Offsets(Cow::Owned(v)))
+    }
+}
+
+fn coord_sum(input: &str, key: Num, nmix: usize, anchor: Num, offsets: &[usize]) -> Result<Num> {
+    let values = parse(input)?
+        .into_iter()
+        .map(|x| {
+            x.checked_mul(key)
+                .ok_or_else(|| anyhow!("{} * {} overflows", x, key))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut m = Mixer::new(values);
     let verbose = crate::verbose();
+    let mut v = m.to_vec();
     if verbose {
-        println!("     {:?}", m.to_vec());
+        println!("     {:?}", v);
     }
-    for i in 0..nmix {
-        m.mix();
+    for (i, r) in m.rounds(nmix).enumerate() {
         if verbose {
-            println!(" {:2}: {:?}", i + 1, m.to_vec());
+            println!(" {:2}: {:?}", i + 1, r);
         }
+        v = r;
     }
-    let v = m.to_vec();
-    let iz = v
-        .iter()
+    // the anchor is given as a number of the input
+    let anchor = anchor
+        .checked_mul(key)
+        .ok_or_else(|| anyhow!("{} * {} overflows", anchor, key))?;
+    Ok(grove_coords(&v, anchor, offsets)?.iter().sum())
+}
+
+fn parse(input: &str) -> Result<Vec<Num>> {
+    input
+        .lines()
         .enumerate()
-        .find_map(|(i, x)| (*x == 0).then_some(i))
-        .ok_or_else(|| anyhow!("zero missing"))?;
-    Ok([1000, 2000, 3000]
-        .iter()
-        .map(|n| v[(iz + n) % v.len()])
-        .sum())
+        .map(|(i, s)| {
+            s.trim()
+                .parse::<Num>()
+                .map_err(|e| anyhow!("line {}: {}", i + 1, e))
+        })
+        .collect()
 }
 
+// Values at the given offsets after the anchor in the circular sequence v.
+// The anchor must appear exactly once.
+fn grove_coords(v: &[Num], anchor: Num, offsets: &[usize]) -> Result<Vec<Num>> {
+    let mut it = v.iter().enumerate().filter(|(_, x)| **x == anchor);
+    let ia = match (it.next(), it.count()) {
+        (Some((i, _)), 0) => i,
+        (None, _) => bail!("{} is missing", anchor),
+        (_, n) => bail!("{} appears {} times instead of once", anchor, n + 1),
+    };
+    Ok(offsets.iter().map(|n| v[(ia + n) % v.len()]).collect())
+}
+
+// Circular sequence of numbers, where mixing moves each number, in their
+// original order, forward or backward by its own value.
 struct Mixer {
-    head: usize,
-    vec: Vec<Node>,
+    seq: Seq,
+    values: Vec<Num>,
 }
 
 impl Mixer {
-    fn from(input: &str, key: Num) -> Result<Mixer> {
-        let v = input
-            .lines()
-            .map(|s| s.parse::<Num>())
-            .collect::<std::result::Result<Vec<Num>, _>>()?;
-        let vl = v.len();
-        Ok(Mixer {
-            head: 0,
-            vec: v
-                .iter()
-                .enumerate()
-                .map(|(i, x)| Node {
-                    pred: (i + vl - 1) % vl,
-                    succ: (i + 1) % vl,
-                    value: *x * key,
-                })
-                .collect(),
+    fn new(values: Vec<Num>) -> Mixer {
+        Mixer {
+            seq: Seq::new(values.len()),
+            values,
+        }
+    }
+
+    // Mixes n times, yielding the sequence after each round.
+    fn rounds(&mut self, n: usize) -> impl Iterator<Item = Vec<Num>> + '_ {
+        (0..n).map(|_| {
+            self.mix();
+            self.to_vec()
         })
     }
 
     fn mix(&mut self) {
-        for i in 0..self.vec.len() {
+        for i in 0..self.values.len() {
             self.shift(i);
         }
     }
 
     fn to_vec(&self) -> Vec<Num> {
-        let mut v = Vec::new();
-        v.reserve(self.vec.len());
-
-        let mut i = self.head;
-        loop {
-            let n = &self.vec[i];
-            v.push(n.value);
-            i = n.succ;
-            if i == self.head {
-                return v;
-            }
-        }
+        self.seq
+            .to_vec()
+            .into_iter()
+            .map(|i| self.values[i])
+            .collect()
     }
 
     fn shift(&mut self, i: usize) {
-        let v = self.vec[i].value;
-        if v == 0 {
+        let v = self.values[i];
+        // wrapping ignores the element being moved
+        let wrap = self.values.len() as Num - 1;
+        if v == 0 || wrap == 0 {
             return;
         }
-        if i == self.head {
-            self.head = self.vec[self.head].succ;
+        let pos = self.seq.position(i);
+        self.seq.remove(pos);
+        let pos = (pos as Num + v).rem_euclid(wrap) as usize;
+        self.seq.insert(pos, i);
+    }
+}
+
+// Sequence of the indices 0..n as an implicit treap, a randomized
+// balanced tree ordered by position, where each node knows its subtree size.
+// Finding the position of an index, removal and insertion take O(log n).
+struct Seq {
+    root: usize,
+    nodes: Vec<SeqNode>,
+}
+
+const NIL: usize = usize::MAX;
+
+struct SeqNode {
+    left: usize,
+    right: usize,
+    parent: usize,
+    size: usize,
+    prio: u32,
+}
+
+impl Seq {
+    fn new(n: usize) -> Seq {
+        let mut rng: u32 = 0x9e3779b9;
+        let nodes = (0..n)
+            .map(|_| {
+                // xorshift32
+                rng ^= rng << 13;
+                rng ^= rng >> 17;
+                rng ^= rng << 5;
+                SeqNode {
+                    left: NIL,
+                    right: NIL,
+                    parent: NIL,
+                    size: 1,
+                    prio: rng,
+                }
+            })
+            .collect();
+        let mut seq = Seq { root: NIL, nodes };
+        for i in 0..n {
+            seq.root = seq.merge(seq.root, i);
         }
-        if v < 0 {
-            self.shift_left(i, self.nshift(-v));
+        seq
+    }
+
+    fn size(&self, t: usize) -> usize {
+        if t == NIL {
+            0
         } else {
-            self.shift_right(i, self.nshift(v));
+            self.nodes[t].size
         }
     }
 
-    fn nshift(&self, n: Num) -> usize {
-        // wrapping ignores the element being moved
-        let wrap = self.vec.len() - 1;
-        ((n as usize) + wrap - 1) % wrap
+    fn update(&mut self, t: usize) {
+        let (l, r) = (self.nodes[t].left, self.nodes[t].right);
+        self.nodes[t].size = 1 + self.size(l) + self.size(r);
+        for c in [l, r] {
+            if c != NIL {
+                self.nodes[c].parent = t;
+            }
+        }
     }
 
-    fn shift_left(&mut self, i: usize, n: usize) {
-        let mut j = self.unlink(i).0;
-        for _ in 0..n {
-            j = self.vec[j].pred;
+    // Splits t into the first k elements and the rest.
+    fn split(&mut self, t: usize, k: usize) -> (usize, usize) {
+        if t == NIL {
+            return (NIL, NIL);
+        }
+        let l = self.nodes[t].left;
+        let r = self.nodes[t].right;
+        let (a, b) = if self.size(l) < k {
+            let (a, b) = self.split(r, k - self.size(l) - 1);
+            self.nodes[t].right = a;
+            self.update(t);
+            (t, b)
+        } else {
+            let (a, b) = self.split(l, k);
+            self.nodes[t].left = b;
+            self.update(t);
+            (a, t)
+        };
+        for x in [a, b] {
+            if x != NIL {
+                self.nodes[x].parent = NIL;
+            }
         }
-        self.link(self.vec[j].pred, i, j);
+        (a, b)
     }
 
-    fn shift_right(&mut self, i: usize, n: usize) {
-        let mut j = self.unlink(i).1;
-        for _ in 0..n {
-            j = self.vec[j].succ;
+    fn merge(&mut self, a: usize, b: usize) -> usize {
+        if a == NIL || b == NIL {
+            return if a == NIL { b } else { a };
+        }
+        if self.nodes[a].prio > self.nodes[b].prio {
+            let r = self.nodes[a].right;
+            self.nodes[a].right = self.merge(r, b);
+            self.update(a);
+            a
+        } else {
+            let l = self.nodes[b].left;
+            self.nodes[b].left = self.merge(a, l);
+            self.update(b);
+            b
         }
-        self.link(j, i, self.vec[j].succ);
     }
 
-    fn unlink(&mut self, i: usize) -> (usize, usize) {
-        let ix = &self.vec[i];
-        let p = ix.pred;
-        let s = ix.succ;
-        let mut px = &mut self.vec[p];
-        px.succ = s;
-        let mut sx = &mut self.vec[s];
-        sx.pred = p;
-        (p, s)
+    fn position(&self, i: usize) -> usize {
+        let mut pos = self.size(self.nodes[i].left);
+        let mut x = i;
+        while self.nodes[x].parent != NIL {
+            let p = self.nodes[x].parent;
+            if self.nodes[p].right == x {
+                pos += self.size(self.nodes[p].left) + 1;
+            }
+            x = p;
+        }
+        pos
     }
 
-    fn link(&mut self, p: usize, i: usize, s: usize) {
-        let mut px = &mut self.vec[p];
-        assert_eq!(px.succ, s);
-        px.succ = i;
-
-        let mut ix = &mut self.vec[i];
-        ix.pred = p;
-        ix.succ = s;
-
-        let mut sx = &mut self.vec[s];
-        assert_eq!(p, sx.pred);
-        sx.pred = i;
+    fn remove(&mut self, pos: usize) -> usize {
+        let (a, b) = self.split(self.root, pos);
+        let (m, c) = self.split(b, 1);
+        self.root = self.merge(a, c);
+        m
     }
-}
 
-struct Node {
-    pred: usize,
-    succ: usize,
+    fn insert(&mut self, pos: usize, i: usize) {
+        let (a, b) = self.split(self.root, pos);
+        let a = self.merge(a, i);
+        self.root = self.merge(a, b);
+    }
 
-    value: Num,
+    fn to_vec(&self) -> Vec<usize> {
+        let mut v = Vec::with_capacity(self.nodes.len());
+        let mut stack = Vec::new();
+        let mut t = self.root;
+        while t != NIL || !stack.is_empty() {
+            while t != NIL {
+                stack.push(t);
+                t = self.nodes[t].left;
+            }
+            let x = stack.pop().unwrap();
+            v.push(x);
+            t = self.nodes[x].right;
+        }
+        v
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::util::Rng;
+    use std::time::Instant;
+
+    // The original linked list mixer, kept for comparison.
+    struct ListMixer {
+        head: usize,
+        vec: Vec<Node>,
+    }
+
+    impl ListMixer {
+        fn from(input: &str, key: Num) -> Result<ListMixer> {
+            let v = input
+                .lines()
+                .map(|s| s.parse::<Num>())
+                .collect::<std::result::Result<Vec<Num>, _>>()?;
+            let vl = v.len();
+            Ok(ListMixer {
+                head: 0,
+                vec: v
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| Node {
+                        pred: (i + vl - 1) % vl,
+                        succ: (i + 1) % vl,
+                        value: *x * key,
+                    })
+                    .collect(),
+            })
+        }
+
+        fn mix(&mut self) {
+            for i in 0..self.vec.len() {
+                self.shift(i);
+            }
+        }
+
+        fn to_vec(&self) -> Vec<Num> {
+            let mut v = Vec::with_capacity(self.vec.len());
+
+            let mut i = self.head;
+            loop {
+                let n = &self.vec[i];
+                v.push(n.value);
+                i = n.succ;
+                if i == self.head {
+                    return v;
+                }
+            }
+        }
+
+        fn shift(&mut self, i: usize) {
+            let v = self.vec[i].value;
+            if v == 0 {
+                return;
+            }
+            if i == self.head {
+                self.head = self.vec[self.head].succ;
+            }
+            if v < 0 {
+                self.shift_left(i, self.nshift(-v));
+            } else {
+                self.shift_right(i, self.nshift(v));
+            }
+        }
+
+        fn nshift(&self, n: Num) -> usize {
+            // wrapping ignores the element being moved
+            let wrap = self.vec.len() - 1;
+            ((n as usize) + wrap - 1) % wrap
+        }
+
+        fn shift_left(&mut self, i: usize, n: usize) {
+            let mut j = self.unlink(i).0;
+            for _ in 0..n {
+                j = self.vec[j].pred;
+            }
+            self.link(self.vec[j].pred, i, j);
+        }
+
+        fn shift_right(&mut self, i: usize, n: usize) {
+            let mut j = self.unlink(i).1;
+            for _ in 0..n {
+                j = self.vec[j].succ;
+            }
+            self.link(j, i, self.vec[j].succ);
+        }
+
+        fn unlink(&mut self, i: usize) -> (usize, usize) {
+            let ix = &self.vec[i];
+            let p = ix.pred;
+            let s = ix.succ;
+            let px = &mut self.vec[p];
+            px.succ = s;
+            let sx = &mut self.vec[s];
+            sx.pred = p;
+            (p, s)
+        }
+
+        fn link(&mut self, p: usize, i: usize, s: usize) {
+            let px = &mut self.vec[p];
+            assert_eq!(px.succ, s);
+            px.succ = i;
+
+            let ix = &mut self.vec[i];
+            ix.pred = p;
+            ix.succ = s;
+
+            let sx = &mut self.vec[s];
+            assert_eq!(p, sx.pred);
+            sx.pred = i;
+        }
+    }
+
+    struct Node {
+        pred: usize,
+        succ: usize,
+
+        value: Num,
+    }
+
+    fn circular_eq(a: &[Num], b: &[Num]) -> bool {
+        a.len() == b.len()
+            && (a.is_empty() || (0..a.len()).any(|r| a.iter().cycle().skip(r).take(a.len()).eq(b)))
+    }
+
+    fn generate(n: usize, seed: u64) -> String {
+        let mut rng = Rng::new(seed);
+        (0..n)
+            .map(|_| (rng.next_bits() as Num % 20001 - 10000).to_string() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn mixer_matches_list() {
+        for (n, seed) in [(2, 2), (3, 3), (7, 4), (50, 5), (333, 6)] {
+            let input = generate(n, seed);
+            let mut m = Mixer::new(
+                parse(&input)
+                    .unwrap()
+                    .iter()
+                    .map(|x| x * DECR_KEY)
+                    .collect(),
+            );
+            let mut l = ListMixer::from(&input, DECR_KEY).unwrap();
+            for _ in 0..3 {
+                m.mix();
+                l.mix();
+                assert!(circular_eq(&m.to_vec(), &l.to_vec()));
+            }
+        }
+    }
+
+    // cargo test --release bench_mix -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_mix() {
+        for n in [5000, 20000] {
+            let input = generate(n, n as u64);
+            let now = Instant::now();
+            let mut m = Mixer::new(
+                parse(&input)
+                    .unwrap()
+                    .iter()
+                    .map(|x| x * DECR_KEY)
+                    .collect(),
+            );
+            for _ in 0..10 {
+                m.mix();
+            }
+            let tm = now.elapsed();
+            let now = Instant::now();
+            let mut l = ListMixer::from(&input, DECR_KEY).unwrap();
+            for _ in 0..10 {
+                l.mix();
+            }
+            let tl = now.elapsed();
+            assert!(circular_eq(&m.to_vec(), &l.to_vec()));
+            println!("n={:6}  tree {:?}  list {:?}", n, tm, tl);
+        }
+    }
 
     #[test]
     fn day20_works() {
         let sample = "1\n2\n-3\n3\n-2\n0\n4\n";
-        assert_eq!(coord_sum(sample, 1, 1).ok(), Some(3));
-        assert_eq!(coord_sum(sample, DECR_KEY, 10).ok(), Some(1623178306));
+        let offsets = OFFSETS.get().unwrap().0;
+        assert_eq!(coord_sum(sample, 1, 1, 0, &offsets).ok(), Some(3));
+        assert_eq!(
+            coord_sum(sample, DECR_KEY, 10, 0, &offsets).ok(),
+            Some(1623178306)
+        );
+        assert_eq!(coord_sum(sample, 1, 1, 4, &[0, 1]).ok(), Some(4));
+        assert_eq!(
+            coord_sum(sample, DECR_KEY, 1, 4, &[0]).ok(),
+            Some(4 * DECR_KEY)
+        );
+
+        let mut m = Mixer::new(parse(sample).unwrap());
+        let rounds: Vec<_> = m.rounds(2).collect();
+        assert!(circular_eq(&rounds[0], &[1, 2, -3, 4, 0, 3, -2]));
+        assert_eq!(
+            grove_coords(&rounds[0], 0, &[1000, 2000, 3000]).ok(),
+            Some(vec![4, -3, 2])
+        );
+        assert_eq!(grove_coords(&rounds[0], 4, &[0, 1]).ok(), Some(vec![4, 0]));
+        assert!(grove_coords(&[1, 0, 2, 0], 0, &[1]).is_err());
+        assert!(grove_coords(&[1, 2], 0, &[1]).is_err());
+
+        let o: Offsets = " 1, 2 ,30".parse().unwrap();
+        assert_eq!(*o.0, [1, 2, 30]);
+        assert!("1,,2".parse::<Offsets>().is_err());
+        assert!("-1".parse::<Offsets>().is_err());
     }
 }
@@ -1,116 +1,380 @@
-use anyhow::{anyhow, Result};
+use crate::param::Param;
+use anyhow::{anyhow, bail, Result};
 use std::collections::HashMap;
 use std::fmt;
+use std::fs;
+use std::rc::Rc;
+
+// File to write the monkey graph to, in Graphviz DOT format.
+const DOT: Param<String> = Param::new("day21.dot", String::new());
 
 pub fn run(input: &str) -> Result<String> {
     let m = parse(input)?;
-    let p1 = Eval::new(&m).root()?;
-    let p2 = find_yell(&m)?;
+    let mut ev = Eval::new(&m)?;
+    let dot = DOT.get()?;
+    if !dot.is_empty() {
+        let (l, r) = root_sides(&m, &ev.order)?;
+        let text = format!("// {} = {}\n{}", l, r, to_dot(&m));
+        fs::write(&dot, text).map_err(|e| anyhow!("writing {:?}: {}", dot, e))?;
+    }
+    let p1 = ev.root()?;
+    let p2 = find_yell(&m, &mut ev)?;
     Ok(format!("{} {}", p1, p2))
 }
 
-fn find_yell(m: &MonkeyMap) -> Result<Num> {
-    find_zero(0, |x| Eval::with_human(m, x).root().unwrap())
-        .ok_or_else(|| anyhow!("can't find what to yell"))
+// Solves root's equality for humn by inverting each operation on the
+// path from root down to humn, using exact rational arithmetic.
+// The solution is checked with the integer evaluator.
+fn find_yell(m: &MonkeyMap, ev: &mut Eval) -> Result<Num> {
+    let (l, r) = root_sides(m, &ev.order)?;
+    let (e, t) = match (&l, &r) {
+        (Expr::Val(a), Expr::Val(b)) => {
+            if a == b {
+                bail!("root holds for any value of humn")
+            } else {
+                bail!("root does not depend on humn and never holds")
+            }
+        }
+        (Expr::Val(t), e) | (e, Expr::Val(t)) => (e, *t),
+        _ => bail!("humn appears on both sides of root"),
+    };
+    let x = e.solve(t)?;
+    let x = x
+        .to_integer()
+        .ok_or_else(|| anyhow!("no integer solution, humn would be {}", x))?;
+
+    let (lm, rm) = root_operands(m)?;
+    ev.set_human(x)?;
+    let (lv, rv) = (ev.value(lm)?, ev.value(rm)?);
+    if lv != rv {
+        bail!("humn = {} gives {} != {} with integer division", x, lv, rv);
+    }
+    Ok(x)
 }
 
-fn find_zero<FN>(start: Num, mut f: FN) -> Option<Num>
-where
-    FN: FnMut(Num) -> Num,
-{
-    let mut p = (start, f(start));
-    let mut q = (0..1_000_000).map(|x| (x, f(x))).find(|(_, y)| *y != p.1)?;
-    let mut dx = q.0 - p.0;
-    let dir = sign(0 - p.0) - sign(q.1 - p.1);
+fn root_operands(m: &MonkeyMap) -> Result<(Monkey, Monkey)> {
+    match m.get(&ROOT) {
+        Some(Yell::Calc(_, l, r)) => Ok((*l, *r)),
+        _ => bail!("root does not compare two monkeys"),
+    }
+}
 
-    while sign(p.1) * sign(q.1) > 0 {
-        let x = q.0 + dir * dx;
-        p = q;
-        q = (x, f(x));
-        dx *= 2;
+// Builds the expressions of both sides of root,
+// order must list every monkey after the ones it listens to.
+fn root_sides(m: &MonkeyMap, order: &[Monkey]) -> Result<(Expr, Expr)> {
+    let (l, r) = root_operands(m)?;
+    let mut exprs: HashMap<Monkey, Rc<Expr>> = HashMap::with_capacity(order.len());
+    for &mky in order {
+        let e = if mky == HUMN {
+            Expr::Human
+        } else {
+            match m[&mky] {
+                Yell::Const(x) => Expr::Val(Ratio::from(x)),
+                Yell::Calc(op, l, r) => {
+                    let (a, b) = (&exprs[&l], &exprs[&r]);
+                    match (a.as_ref(), b.as_ref()) {
+                        (Expr::Val(a), Expr::Val(b)) => Expr::Val(op.apply(*a, *b)?),
+                        _ => Expr::Calc(op, a.clone(), b.clone()),
+                    }
+                }
+            }
+        };
+        exprs.insert(mky, Rc::new(e));
     }
+    let side = |mky| {
+        exprs
+            .get(&mky)
+            .map(|e| e.as_ref().clone())
+            .ok_or_else(|| anyhow!("invalid monkey {}", mky))
+    };
+    Ok((side(l)?, side(r)?))
+}
+
+// Expression with humn as the only variable,
+// and every subtree without humn folded into a value.
+#[derive(Debug, Clone)]
+enum Expr {
+    Val(Ratio),
+    Human,
+    Calc(Op, Rc<Expr>, Rc<Expr>),
+}
 
-    loop {
-        if p.0 + 1 == q.0 {
-            return None;
+impl Expr {
+    // Finds the value of humn for which self equals target.
+    fn solve(&self, target: Ratio) -> Result<Ratio> {
+        let mut e = self;
+        let mut t = target;
+        loop {
+            match e {
+                Expr::Human => return Ok(t),
+                Expr::Val(_) => bail!("expression does not depend on humn"),
+                Expr::Calc(op, l, r) => match (l.as_ref(), r.as_ref()) {
+                    (x, Expr::Val(k)) => {
+                        t = op.solve_left(t, *k)?;
+                        e = x;
+                    }
+                    (Expr::Val(k), x) => {
+                        t = op.solve_right(*k, t)?;
+                        e = x;
+                    }
+                    _ => bail!("humn appears on both sides of {}", e),
+                },
+            }
         }
+    }
 
-        let x = (p.0 + q.0) / 2;
-        let m = (x, f(x));
-        if m.1 == 0 {
-            return Some(m.0);
+    fn prec(&self) -> u8 {
+        match self {
+            Expr::Calc(Op::Add | Op::Sub, _, _) => 1,
+            Expr::Calc(Op::Mul | Op::Div, _, _) => 2,
+            Expr::Val(x) if !x.is_integer() => 2,
+            _ => 3,
         }
+    }
+}
 
-        if sign(p.1) * sign(m.1) > 0 {
-            p = m;
-        } else {
-            q = m;
+impl Drop for Expr {
+    // Unlinks children iteratively, so that dropping
+    // a deep expression does not overflow the stack.
+    fn drop(&mut self) {
+        let mut stack = Vec::new();
+        let take = |e: &mut Expr, stack: &mut Vec<Rc<Expr>>| {
+            if let Expr::Calc(_, l, r) = e {
+                stack.push(std::mem::replace(l, Rc::new(Expr::Human)));
+                stack.push(std::mem::replace(r, Rc::new(Expr::Human)));
+            }
+        };
+        take(self, &mut stack);
+        while let Some(rc) = stack.pop() {
+            if let Ok(mut e) = Rc::try_unwrap(rc) {
+                take(&mut e, &mut stack);
+            }
         }
     }
 }
 
-fn sign(x: Num) -> Num {
-    if x != 0 {
-        if x < 0 {
-            -1
-        } else {
-            1
+impl fmt::Display for Expr {
+    // Writes from an explicit stack, so that deep expressions
+    // do not overflow the stack either.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        enum Part<'a> {
+            Expr(&'a Expr),
+            Text(&'static str),
+            Op(Op),
+        }
+        // parts are pushed in reverse order
+        fn operand<'a>(stack: &mut Vec<Part<'a>>, e: &'a Expr, min: u8) {
+            if e.prec() < min {
+                stack.extend([Part::Text(")"), Part::Expr(e), Part::Text("(")]);
+            } else {
+                stack.push(Part::Expr(e));
+            }
+        }
+
+        let mut stack = vec![Part::Expr(self)];
+        while let Some(part) = stack.pop() {
+            match part {
+                Part::Text(s) => f.write_str(s)?,
+                Part::Op(op) => write!(f, " {} ", op)?,
+                Part::Expr(Expr::Val(x)) => write!(f, "{}", x)?,
+                Part::Expr(Expr::Human) => write!(f, "{}", HUMN)?,
+                Part::Expr(e @ Expr::Calc(op, l, r)) => {
+                    let p = e.prec();
+                    // the right operand of - and / needs parentheses on equal precedence
+                    let rp = if matches!(op, Op::Sub | Op::Div) {
+                        p + 1
+                    } else {
+                        p
+                    };
+                    operand(&mut stack, r, rp);
+                    stack.push(Part::Op(*op));
+                    operand(&mut stack, l, p);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Orders the monkeys root depends on so that every monkey comes after
+// the ones it listens to. An undefined humn is a leaf to be set later.
+// Fails on references to other undefined monkeys and on cycles.
+fn topo_order(m: &MonkeyMap) -> Result<Vec<Monkey>> {
+    #[derive(PartialEq)]
+    enum Visit {
+        Active,
+        Done,
+    }
+
+    if !m.contains_key(&ROOT) {
+        bail!("{} is missing", ROOT);
+    }
+    let mut visit = HashMap::new();
+    let mut order = Vec::with_capacity(m.len());
+    visit.insert(ROOT, Visit::Active);
+    let mut path = vec![(ROOT, 0)];
+    while let Some((mky, i)) = path.last_mut() {
+        let deps = m.get(mky).map_or_else(Vec::new, |y| y.deps());
+        if *i == deps.len() {
+            visit.insert(*mky, Visit::Done);
+            order.push(*mky);
+            path.pop();
+            continue;
+        }
+        let (mky, dep) = (*mky, deps[*i]);
+        *i += 1;
+        match visit.get(&dep) {
+            Some(Visit::Done) => {}
+            Some(Visit::Active) => {
+                let pos = path.iter().position(|(x, _)| *x == dep).unwrap_or(0);
+                let cycle = path[pos..]
+                    .iter()
+                    .map(|(x, _)| x.to_string())
+                    .chain(std::iter::once(dep.to_string()))
+                    .collect::<Vec<_>>();
+                bail!("monkeys listen in a cycle: {}", cycle.join(" -> "));
+            }
+            None => {
+                if !m.contains_key(&dep) && dep != HUMN {
+                    bail!("monkey {} listens to undefined monkey {}", mky, dep);
+                }
+                visit.insert(dep, Visit::Active);
+                path.push((dep, 0));
+            }
+        }
+    }
+    Ok(order)
+}
+
+// Exports the dependency graph in Graphviz DOT format.
+fn to_dot(m: &MonkeyMap) -> String {
+    let mut keys: Vec<Monkey> = m.keys().copied().collect();
+    keys.sort();
+
+    let mut s = String::from("digraph monkeys {\n");
+    for mky in keys {
+        match m[&mky] {
+            Yell::Const(x) => {
+                s += &format!(
+                    "  {} [label=\"{}\\n{}\"];\n",
+                    dot_id(mky),
+                    dot_escape(mky),
+                    x
+                )
+            }
+            Yell::Calc(op, l, r) => {
+                s += &format!(
+                    "  {} [label=\"{}\\n{}\" shape=box];\n",
+                    dot_id(mky),
+                    dot_escape(mky),
+                    op
+                );
+                let (mky, l, r) = (dot_id(mky), dot_id(l), dot_id(r));
+                s += &format!("  {} -> {};\n  {} -> {};\n", mky, l, mky, r);
+            }
         }
-    } else {
-        0
     }
+    s += &format!("  {} [style=filled];\n", dot_id(HUMN));
+    s += "}\n";
+    s
+}
+
+// Quoted, so that names like node or edge are not taken for keywords.
+fn dot_id(mky: Monkey) -> String {
+    format!("\"{}\"", dot_escape(mky))
+}
+
+fn dot_escape(mky: Monkey) -> String {
+    mky.to_string().replace('\\', "\\\\").replace('"', "\\\"")
 }
 
 type MonkeyMap = HashMap<Monkey, Yell>;
 
-struct Eval<'a> {
-    m: &'a MonkeyMap,
-    humn: Option<Num>,
+// Evaluates the monkeys root depends on in topological order and keeps
+// every value, so that a new humn value only recomputes the monkeys
+// depending on it. Without a humn value those monkeys are left unknown.
+struct Eval {
+    order: Vec<Monkey>,
+    index: HashMap<Monkey, usize>,
+    calc: Vec<Option<(Op, usize, usize)>>,
+    values: Vec<Num>,
+    human: Vec<usize>,
+    human_known: bool,
 }
 
 static ROOT: Monkey = Monkey::from_chars('r', 'o', 'o', 't');
 static HUMN: Monkey = Monkey::from_chars('h', 'u', 'm', 'n');
 
-impl<'a> Eval<'a> {
-    fn new(m: &'a MonkeyMap) -> Self {
-        Self { m, humn: None }
-    }
-
-    fn with_human(m: &'a MonkeyMap, humn: Num) -> Self {
-        Self {
-            m,
-            humn: Some(humn),
+impl Eval {
+    fn new(m: &MonkeyMap) -> Result<Self> {
+        let order = topo_order(m)?;
+        let index: HashMap<Monkey, usize> =
+            order.iter().enumerate().map(|(i, mky)| (*mky, i)).collect();
+        let mut calc = Vec::with_capacity(order.len());
+        let mut values = Vec::with_capacity(order.len());
+        let mut dep = Vec::with_capacity(order.len());
+        let mut human = Vec::new();
+        let human_known = m.contains_key(&HUMN);
+        for (i, mky) in order.iter().enumerate() {
+            let (c, v, d) = match m.get(mky) {
+                None => (None, 0, true),
+                Some(&Yell::Const(x)) => (None, x, *mky == HUMN),
+                Some(&Yell::Calc(op, l, r)) => {
+                    let (l, r) = (index[&l], index[&r]);
+                    let d = dep[l] || dep[r];
+                    let v = if d && !human_known {
+                        0
+                    } else {
+                        op.calc(values[l], values[r])?
+                    };
+                    (Some((op, l, r)), v, d)
+                }
+            };
+            calc.push(c);
+            values.push(v);
+            dep.push(d);
+            if d {
+                human.push(i);
+            }
         }
+        Ok(Self {
+            order,
+            index,
+            calc,
+            values,
+            human,
+            human_known,
+        })
     }
 
     fn root(&self) -> Result<Num> {
-        self.eval(ROOT)
+        self.value(ROOT)
     }
 
-    fn eval(&self, mky: Monkey) -> Result<Num> {
-        let yell = self
-            .m
+    fn value(&self, mky: Monkey) -> Result<Num> {
+        let i = *self
+            .index
             .get(&mky)
-            .ok_or_else(|| anyhow!("invalid monkey {}", mky.to_string()))?;
-        Ok(match yell {
-            Yell::Const(x) => {
-                let humn = if mky == HUMN { self.humn } else { None };
-                if let Some(z) = humn {
-                    z
-                } else {
-                    *x
-                }
-            }
-            Yell::Calc(op, l, r) => {
-                let l = self.eval(*l)?;
-                let r = self.eval(*r)?;
-                if mky == ROOT && self.humn.is_some() {
-                    l - r
-                } else {
-                    op.calc(l, r)
-                }
+            .ok_or_else(|| anyhow!("invalid monkey {}", mky))?;
+        if !self.human_known && self.human.binary_search(&i).is_ok() {
+            bail!("{} is missing, {} depends on it", HUMN, mky);
+        }
+        Ok(self.values[i])
+    }
+
+    fn set_human(&mut self, x: Num) -> Result<()> {
+        let h = *self
+            .index
+            .get(&HUMN)
+            .ok_or_else(|| anyhow!("{} is missing", HUMN))?;
+        self.values[h] = x;
+        self.human_known = true;
+        for &i in &self.human {
+            if let Some((op, l, r)) = self.calc[i] {
+                self.values[i] = op.calc(self.values[l], self.values[r])?;
             }
-        })
+        }
+        Ok(())
     }
 }
 
@@ -128,16 +392,12 @@ fn parse(input: &str) -> Result<MonkeyMap> {
         .collect()
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 struct Monkey(u32);
 
 impl Monkey {
     fn from(s: &str) -> Monkey {
-        Monkey(
-            s.chars()
-                .map(|c| (c as u32))
-                .fold(0, |acc, x| (acc << 8) | x),
-        )
+        Monkey(s.chars().map(|c| c as u32).fold(0, |acc, x| (acc << 8) | x))
     }
 
     const fn from_chars(a: char, b: char, c: char, d: char) -> Monkey {
@@ -175,17 +435,169 @@ enum Op {
 }
 
 impl Op {
-    fn calc(self, l: Num, r: Num) -> Num {
+    fn calc(self, l: Num, r: Num) -> Result<Num> {
+        match self {
+            Self::Add => l.checked_add(r),
+            Self::Sub => l.checked_sub(r),
+            Self::Mul => l.checked_mul(r),
+            Self::Div => l.checked_div(r),
+        }
+        .ok_or_else(|| anyhow!("can't calculate {} {} {}", l, self, r))
+    }
+
+    fn apply(self, l: Ratio, r: Ratio) -> Result<Ratio> {
+        match self {
+            Self::Add => l.add(r),
+            Self::Sub => l.sub(r),
+            Self::Mul => l.mul(r),
+            Self::Div => l.div(r),
+        }
+    }
+
+    // Solves x op k = t for x.
+    fn solve_left(self, t: Ratio, k: Ratio) -> Result<Ratio> {
+        match self {
+            Self::Add => t.sub(k),
+            Self::Sub => t.add(k),
+            Self::Mul => Self::solve_mul(t, k),
+            Self::Div => {
+                if k.is_zero() {
+                    bail!("division by zero");
+                }
+                t.mul(k)
+            }
+        }
+    }
+
+    // Solves k op x = t for x.
+    fn solve_right(self, k: Ratio, t: Ratio) -> Result<Ratio> {
         match self {
-            Self::Add => l + r,
-            Self::Sub => l - r,
-            Self::Mul => l * r,
-            Self::Div => l / r,
+            Self::Add => t.sub(k),
+            Self::Sub => k.sub(t),
+            Self::Mul => Self::solve_mul(t, k),
+            Self::Div => match (k.is_zero(), t.is_zero()) {
+                (true, true) => bail!("any nonzero divisor solves 0 / x = 0"),
+                (false, false) => k.div(t),
+                _ => bail!("{} / x = {} has no solution", k, t),
+            },
+        }
+    }
+
+    fn solve_mul(t: Ratio, k: Ratio) -> Result<Ratio> {
+        match (k.is_zero(), t.is_zero()) {
+            (true, true) => bail!("any value solves x * 0 = 0"),
+            (true, false) => bail!("x * 0 = {} has no solution", t),
+            _ => t.div(k),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+        })
+    }
+}
+
+// Exact fraction, always kept in lowest terms with a positive denominator.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Ratio {
+    n: i128,
+    d: i128,
+}
+
+impl Ratio {
+    fn new(n: i128, d: i128) -> Result<Ratio> {
+        if d == 0 {
+            bail!("division by zero");
+        }
+        let g = gcd(n, d);
+        let s = if d < 0 { -1 } else { 1 };
+        Ok(Ratio {
+            n: s * n / g,
+            d: s * d / g,
+        })
+    }
+
+    fn is_zero(&self) -> bool {
+        self.n == 0
+    }
+
+    fn is_integer(&self) -> bool {
+        self.d == 1
+    }
+
+    fn to_integer(self) -> Option<Num> {
+        (self.d == 1).then(|| Num::try_from(self.n).ok()).flatten()
+    }
+
+    fn checked(n: Option<i128>, d: Option<i128>, l: Ratio, op: Op, r: Ratio) -> Result<Ratio> {
+        match (n, d) {
+            (Some(n), Some(d)) => Ratio::new(n, d),
+            _ => bail!("overflow calculating {} {} {}", l, op, r),
+        }
+    }
+
+    fn add(self, o: Ratio) -> Result<Ratio> {
+        let n = (self.n.checked_mul(o.d))
+            .and_then(|a| o.n.checked_mul(self.d).and_then(|b| a.checked_add(b)));
+        Self::checked(n, self.d.checked_mul(o.d), self, Op::Add, o)
+    }
+
+    fn sub(self, o: Ratio) -> Result<Ratio> {
+        self.add(Ratio { n: -o.n, d: o.d })
+    }
+
+    fn mul(self, o: Ratio) -> Result<Ratio> {
+        let n = self.n.checked_mul(o.n);
+        Self::checked(n, self.d.checked_mul(o.d), self, Op::Mul, o)
+    }
+
+    fn div(self, o: Ratio) -> Result<Ratio> {
+        if o.is_zero() {
+            bail!("division by zero: {} / {}", self, o);
+        }
+        let n = self.n.checked_mul(o.d);
+        Self::checked(n, self.d.checked_mul(o.n), self, Op::Div, o)
+    }
+}
+
+impl From<Num> for Ratio {
+    fn from(x: Num) -> Self {
+        Ratio { n: x as i128, d: 1 }
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_integer() {
+            write!(f, "{}", self.n)
+        } else {
+            write!(f, "{}/{}", self.n, self.d)
         }
     }
 }
 
+fn gcd(a: i128, b: i128) -> i128 {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.max(1)
+}
+
 impl Yell {
+    fn deps(&self) -> Vec<Monkey> {
+        match *self {
+            Yell::Const(_) => Vec::new(),
+            Yell::Calc(_, l, r) => vec![l, r],
+        }
+    }
+
     fn parse(s: &str) -> Option<Yell> {
         let s = s.trim();
         if let Ok(x) = s.parse::<Num>() {
@@ -204,3 +616,144 @@ impl Yell {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn day21_works() {
+        let m = parse(SAMPLE).unwrap();
+        let mut ev = Eval::new(&m).unwrap();
+        assert_eq!(ev.root().ok(), Some(152));
+        assert_eq!(find_yell(&m, &mut ev).ok(), Some(301));
+        assert_eq!(ev.value(Monkey::from("pppw")).ok(), Some(150));
+    }
+
+    fn solve(input: &str) -> Result<Num> {
+        let m = parse(input)?;
+        find_yell(&m, &mut Eval::new(&m)?)
+    }
+
+    fn sides(input: &str) -> (String, String) {
+        let m = parse(input).unwrap();
+        let (l, r) = root_sides(&m, &topo_order(&m).unwrap()).unwrap();
+        (l.to_string(), r.to_string())
+    }
+
+    #[test]
+    fn find_yell_errors() {
+        assert_eq!(solve("root: humn + abcd\nhumn: 5\nabcd: 0\n").ok(), Some(0));
+        assert!(solve("root: aaaa + bbbb\naaaa: humn * 2\nhumn: 5\nbbbb: 3\n").is_err());
+        assert!(
+            solve("root: aaaa + bbbb\naaaa: humn * zero\nhumn: 5\nbbbb: 0\nzero: 0\n").is_err()
+        );
+        assert!(solve("root: aaaa + bbbb\naaaa: humn * humn\nhumn: 5\nbbbb: 4\n").is_err());
+        // 7 / 2 is 3 in integer division, but not for the exact solution
+        assert!(
+            solve("root: aaaa + bbbb\naaaa: humn - cccc\ncccc: 7 / 2\nhumn: 5\nbbbb: 1\n").is_err()
+        );
+    }
+
+    #[test]
+    fn deep_tree() {
+        // z??? names do not collide with the fixed ones
+        let name = |i: usize| -> String {
+            std::iter::once('z')
+                .chain((0..3).map(|k| (b'a' + (i / 26usize.pow(k) % 26) as u8) as char))
+                .collect()
+        };
+        let n = 15000;
+        let mut input = format!("root: {} + bbbb\nbbbb: {}\nhumn: 1\n", name(0), 3 * n);
+        for i in 0..n {
+            let next = if i + 1 < n {
+                name(i + 1)
+            } else {
+                "humn".to_string()
+            };
+            input += &format!("{}: {} + cccc\n", name(i), next);
+        }
+        input += "cccc: 2\n";
+        let m = parse(&input).unwrap();
+        let mut ev = Eval::new(&m).unwrap();
+        assert_eq!(
+            ev.value(Monkey::from(&name(0))).ok(),
+            Some(1 + 2 * n as Num)
+        );
+        assert_eq!(find_yell(&m, &mut ev).unwrap(), n as Num);
+        let (l, r) = root_sides(&m, &ev.order).unwrap();
+        let l = l.to_string();
+        assert!(l.starts_with("humn + 2 + 2 + "));
+        assert_eq!(l.len(), "humn".len() + n * " + 2".len());
+        assert_eq!(r.to_string(), (3 * n).to_string());
+    }
+
+    #[test]
+    fn expr_works() {
+        let (l, r) = sides(SAMPLE);
+        assert_eq!(l, "(4 + 2 * (humn - 3)) / 4");
+        assert_eq!(r, "150");
+
+        let (l, _) = sides("root: aaaa - bbbb\naaaa: humn - bbbb\nbbbb: humn / cccc\ncccc: 3\n");
+        assert_eq!(l, "humn - humn / 3");
+
+        let dot = to_dot(&parse(SAMPLE).unwrap());
+        assert!(dot.starts_with("digraph monkeys {\n"));
+        assert!(dot.contains("  \"root\" -> \"pppw\";\n  \"root\" -> \"sjmn\";\n"));
+        assert!(dot.contains("  \"humn\" [style=filled];\n"));
+        // node and edge are DOT keywords
+        let input = "root: node + edge\nnode: 1\nedge: a\"bc * node\na\"bc: 3\n";
+        let dot = to_dot(&parse(input).unwrap());
+        assert!(dot.contains("  \"node\" [label=\"node\\n1\"];\n"));
+        assert!(dot.contains("  \"edge\" -> \"a\\\"bc\";\n"));
+    }
+
+    #[test]
+    fn topo_order_errors() {
+        let m = parse("root: aaaa + bbbb\naaaa: 1\nbbbb: cccc * 2\n").unwrap();
+        let e = topo_order(&m).unwrap_err().to_string();
+        assert_eq!(e, "monkey bbbb listens to undefined monkey cccc");
+        let m = parse("root: aaaa + bbbb\naaaa: 1\nbbbb: cccc * aaaa\ncccc: bbbb + aaaa\n");
+        let e = topo_order(&m.unwrap()).unwrap_err().to_string();
+        assert_eq!(e, "monkeys listen in a cycle: bbbb -> cccc -> bbbb");
+        assert_eq!(topo_order(&parse(SAMPLE).unwrap()).unwrap().len(), 15);
+        let m = parse("aaaa: 1\n").unwrap();
+        assert_eq!(topo_order(&m).unwrap_err().to_string(), "root is missing");
+    }
+
+    #[test]
+    fn partial_input() {
+        // humn may be left undefined, only part 1 needs its value
+        let input = "root: aaaa + bbbb\naaaa: humn * two\ntwo: 2\nbbbb: 6\n";
+        let m = parse(input).unwrap();
+        let mut ev = Eval::new(&m).unwrap();
+        let e = ev.root().unwrap_err().to_string();
+        assert_eq!(e, "humn is missing, root depends on it");
+        assert_eq!(ev.value(Monkey::from("bbbb")).ok(), Some(6));
+        assert_eq!(find_yell(&m, &mut ev).ok(), Some(3));
+        assert_eq!(ev.root().ok(), Some(12));
+
+        // monkeys root does not listen to are not evaluated
+        let input = "root: aaaa + bbbb\naaaa: 1\nbbbb: 2\nzzzz: aaaa / zero\nzero: 0\n";
+        let ev = Eval::new(&parse(input).unwrap()).unwrap();
+        assert_eq!(ev.root().ok(), Some(3));
+    }
+
+    const SAMPLE: &str = "\
+root: pppw + sjmn
+dbpl: 5
+cczh: sllz + lgvd
+zczc: 2
+ptdq: humn - dvpt
+dvpt: 3
+lfqf: 4
+humn: 5
+ljgn: 2
+sjmn: drzm * dbpl
+sllz: 4
+pppw: cczh / lfqf
+lgvd: ljgn * ptdq
+drzm: hmdt - zczc
+hmdt: 32
+";
+}
@@ -1,10 +1,13 @@
+use crate::param::Param;
 use anyhow::{anyhow, Result};
 use std::collections::{HashMap, HashSet, VecDeque};
 
+const CUBE: Param<Coord> = Param::new("day22.cube", 50);
+
 pub fn run(input: &str) -> Result<String> {
     let (m, instr) = parse(input).ok_or_else(|| anyhow!("parse error"))?;
     let p1 = walk(&m, &instr);
-    let p2 = cube_walk(&m, 50, &instr)?;
+    let p2 = cube_walk(&m, CUBE.get()?, &instr)?;
     Ok(format!("{} {}", p1, p2))
 }
 
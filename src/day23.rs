@@ -1,15 +1,37 @@
-use anyhow::Result;
-use std::collections::{HashMap, HashSet};
+use crate::param::Param;
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const SHOW: Param<Rounds> = Param::new("day23.show", Rounds(Vec::new()));
+const FRAMES: Param<String> = Param::new("day23.frames", String::new());
+const RULES: Param<String> = Param::new("day23.rules", String::new());
+const MAX_ROUNDS: Param<usize> = Param::new("day23.max_rounds", 100_000);
 
 pub fn run(input: &str) -> Result<String> {
-    let p1 = sim_step_area(input, 10);
-    let p2 = find_stop_round(input);
+    let rules = match RULES.get()?.as_str() {
+        "" => Rules::default(),
+        path => fs::read_to_string(path)
+            .map_err(|e| anyhow!("reading {:?}: {}", path, e))?
+            .parse()
+            .map_err(|e| anyhow!("rules in {:?}: {}", path, e))?,
+    };
+    let p1 = sim_step_area(input, &rules, 10);
+    let render = Render {
+        rounds: SHOW.get()?,
+        dir: Some(FRAMES.get()?)
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from),
+    };
+    let p2 = find_stop_round(input, &rules, &render, MAX_ROUNDS.get()?)?;
     Ok(format!("{} {}", p1, p2))
 }
 
-const SHOW_STEPS: bool = false;
-fn sim_step_area(input: &str, n: usize) -> usize {
-    let mut s = Sim::from(input);
+fn sim_step_area(input: &str, rules: &Rules, n: usize) -> usize {
+    let mut s = Sim::from(input, rules);
     for _ in 0..n {
         s.step();
         if crate::verbose() {
@@ -19,112 +41,344 @@ fn sim_step_area(input: &str, n: usize) -> usize {
     s.count_free()
 }
 
-fn find_stop_round(input: &str) -> usize {
-    let mut s = Sim::from(input);
-    while s.step() {}
-    s.n
+fn find_stop_round(input: &str, rules: &Rules, render: &Render, max: usize) -> Result<usize> {
+    let mut s = Sim::from(input, rules);
+    render.round(&s, 0)?;
+    while s.n < max {
+        let moved = s.step();
+        render.round(&s, moved)?;
+        if moved == 0 {
+            return Ok(s.n);
+        }
+    }
+    bail!("elves still moving after {} rounds", max)
+}
+
+// Rounds to render, e.g. "*" for all of them, "*/10" for every tenth
+// or "0,5-8,20" for a selection. Round 0 is the initial position.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Rounds(Vec<RoundSel>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RoundSel {
+    Every(usize),
+    Range(usize, usize),
+}
+
+impl Rounds {
+    fn contains(&self, n: usize) -> bool {
+        self.0.iter().any(|sel| match *sel {
+            RoundSel::Every(k) => n.is_multiple_of(k),
+            RoundSel::Range(a, b) => (a..=b).contains(&n),
+        })
+    }
+}
+
+impl FromStr for Rounds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Rounds> {
+        let num = |x: &str| {
+            x.trim()
+                .parse::<usize>()
+                .map_err(|e| anyhow!("invalid round {:?}: {}", x, e))
+        };
+        s.split(',')
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| {
+                let x = x.trim();
+                Ok(if x == "*" {
+                    RoundSel::Every(1)
+                } else if let Some(k) = x.strip_prefix("*/") {
+                    match num(k)? {
+                        0 => bail!("invalid round step 0"),
+                        k => RoundSel::Every(k),
+                    }
+                } else if let Some((a, b)) = x.split_once('-') {
+                    RoundSel::Range(num(a)?, num(b)?)
+                } else {
+                    let a = num(x)?;
+                    RoundSel::Range(a, a)
+                })
+            })
+            .collect::<Result<_>>()
+            .map(Rounds)
+    }
+}
+
+// Writes the selected rounds to the terminal, or as text frames
+// into a directory.
+#[derive(Debug, Default)]
+struct Render {
+    rounds: Rounds,
+    dir: Option<PathBuf>,
+}
+
+impl Render {
+    fn round(&self, s: &Sim, moved: usize) -> Result<()> {
+        if !self.rounds.contains(s.n) {
+            return Ok(());
+        }
+        let text = format!("{}\n{}", s.stats(moved), s.to_string_lines());
+        match &self.dir {
+            None => {
+                println!("== Round {} ==\n{}", s.n, text);
+            }
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                let path = dir.join(format!("round{:05}.txt", s.n));
+                fs::write(&path, text).map_err(|e| anyhow!("writing {:?}: {}", path, e))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 struct Sim {
-    m: HashSet<Vec2>,
+    g: Grid,
     n: usize,
+    rules: Rules,
 }
 
 impl Sim {
-    fn from(input: &str) -> Sim {
+    fn from(input: &str, rules: &Rules) -> Sim {
         Sim {
-            m: poss(input).collect(),
+            g: Grid::from_points(poss(input), MARGIN),
             n: 0,
+            rules: rules.clone(),
         }
     }
 
-    fn step(&mut self) -> bool {
-        let mut goal = HashMap::new();
-        for (_, p) in self.nexts() {
-            if let Some(x) = p {
-                goal.entry(x).and_modify(|n| *n += 1).or_insert(1);
-            }
+    // Runs a round and returns the number of elves that moved.
+    fn step(&mut self) -> usize {
+        let rules = &self.rules;
+        if !self.g.fits(rules.reach()) {
+            self.g = Grid::from_points(self.g.points(), MARGIN);
         }
+        let g = &self.g;
+        let cur = &g.bits;
+        let layers: HashMap<Vec2, Vec<u64>> =
+            rules.offsets().map(|d| (d, g.offset(cur, d))).collect();
 
-        let mut moved = false;
-        let m2 = self
-            .nexts()
-            .map(|(e, p)| {
-                if let Some(x) = p {
-                    if goal.get(&x) == Some(&1) {
-                        moved = true;
-                        return x;
-                    }
-                }
-                e
+        let crowded = rules
+            .nbors
+            .iter()
+            .fold(g.empty(), |a, d| or(&a, &layers[d]));
+        let mut left = and(cur, &crowded);
+        let props: Vec<_> = rules
+            .order(self.n)
+            .map(|p| {
+                let blocked = p.check.iter().fold(g.empty(), |a, d| or(&a, &layers[d]));
+                let m = and_not(&left, &blocked);
+                left = and_not(&left, &m);
+                (p.step, m)
             })
             .collect();
 
-        self.m = m2;
+        // targets proposed by one elf, and by more than one
+        let (mut once, mut twice) = (g.empty(), g.empty());
+        for (step, m) in &props {
+            let t = g.offset(m, neg(*step));
+            twice = or(&twice, &and(&once, &t));
+            once = or(&once, &t);
+        }
+
+        let mut next = cur.clone();
+        let mut moved = 0;
+        for (step, m) in &props {
+            let ok = and_not(m, &g.offset(&twice, *step));
+            moved += ok.iter().map(|w| w.count_ones() as usize).sum::<usize>();
+            next = or(&and_not(&next, &ok), &g.offset(&ok, neg(*step)));
+        }
+
+        self.g.bits = next;
         self.n += 1;
 
         moved
     }
 
+    fn stats(&self, moved: usize) -> String {
+        let elves = self.g.len();
+        match self.g.bbox() {
+            Some((p0, p1)) => format!(
+                "{} elves, {} moved, box {:?}-{:?} {}x{}, {} empty",
+                elves,
+                moved,
+                p0,
+                p1,
+                p1.0 + 1 - p0.0,
+                p1.1 + 1 - p0.1,
+                area(p0, p1) - elves
+            ),
+            None => "no elves".to_string(),
+        }
+    }
+
     fn count_free(&self) -> usize {
-        let n = self.m.len();
-        if n == 0 {
-            return 0;
-        }
-        let x0 = self.m.iter().map(|p| p.0).min().unwrap();
-        let x1 = self.m.iter().map(|p| p.0).max().unwrap();
-        let y0 = self.m.iter().map(|p| p.1).min().unwrap();
-        let y1 = self.m.iter().map(|p| p.1).max().unwrap();
-        let a = ((x1 + 1 - x0) as usize) * ((y1 + 1 - y0) as usize);
-        a - n
-    }
-
-    fn nexts(&self) -> impl Iterator<Item = (Vec2, Option<Vec2>)> + '_ {
-        let l = PROPOSALS.len();
-        self.m.iter().map(move |&e| {
-            if self.stay(e) {
-                return (e, None);
+        match self.g.bbox() {
+            Some((p0, p1)) => area(p0, p1) - self.g.len(),
+            None => 0,
+        }
+    }
+
+    fn to_string_lines(&self) -> String {
+        let Some((p0, p1)) = self.g.bbox() else {
+            return String::new();
+        };
+        let mut s = String::new();
+        for y in p0.1..=p1.1 {
+            for x in p0.0..=p1.0 {
+                s.push(if self.g.get((x, y)) { '#' } else { '.' });
             }
-            let p = (0..l)
-                .map(|i| PROPOSALS[(i + self.n) % l])
-                .find_map(|p| self.proposal(e, &p));
-            (e, p)
-        })
+            s.push('\n');
+        }
+        s
     }
+}
 
-    fn stay(&self, e: Vec2) -> bool {
-        NBORS
-            .iter()
-            .all(|d| !self.m.contains(&(e.0 + d.0, e.1 + d.1)))
+// Free tiles kept around the elves when the grid is (re)allocated.
+const MARGIN: i32 = 32;
+
+// Tiles as rows of bits: bit x of row y is the tile (x0 + x, y0 + y).
+#[derive(Debug, Clone)]
+struct Grid {
+    x0: i32,
+    y0: i32,
+    w: usize, // words per row
+    h: usize,
+    bits: Vec<u64>,
+}
+
+impl Grid {
+    fn from_points(ps: impl IntoIterator<Item = Vec2>, margin: i32) -> Grid {
+        let ps: Vec<_> = ps.into_iter().collect();
+        let x0 = ps.iter().map(|p| p.0).min().unwrap_or(0) - margin;
+        let x1 = ps.iter().map(|p| p.0).max().unwrap_or(0) + margin;
+        let y0 = ps.iter().map(|p| p.1).min().unwrap_or(0) - margin;
+        let y1 = ps.iter().map(|p| p.1).max().unwrap_or(0) + margin;
+        let w = ((x1 - x0) as usize) / 64 + 1;
+        let h = (y1 - y0) as usize + 1;
+        let mut g = Grid {
+            x0,
+            y0,
+            w,
+            h,
+            bits: vec![0; w * h],
+        };
+        for p in ps {
+            let i = g.index(p).unwrap();
+            g.bits[i / 64] |= 1 << (i % 64);
+        }
+        g
     }
 
-    fn proposal(&self, e: Vec2, p: &Proposal) -> Option<Vec2> {
-        (!p.check
-            .iter()
-            .any(|x| self.m.contains(&(e.0 + x.0, e.1 + x.1))))
-        .then_some((e.0 + p.step.0, e.1 + p.step.1))
+    fn empty(&self) -> Vec<u64> {
+        vec![0; self.bits.len()]
     }
 
-    fn to_string_lines(&self) -> String {
-        if self.m.is_empty() {
-            return String::new();
+    // Bit index of tile p, if it is on the grid.
+    fn index(&self, p: Vec2) -> Option<usize> {
+        let (x, y) = ((p.0 - self.x0) as usize, (p.1 - self.y0) as usize);
+        (x < self.w * 64 && y < self.h).then_some(y * self.w * 64 + x)
+    }
+
+    fn get(&self, p: Vec2) -> bool {
+        self.index(p)
+            .is_some_and(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    fn len(&self) -> usize {
+        self.bits.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    fn points(&self) -> Vec<Vec2> {
+        let mut v = Vec::with_capacity(self.len());
+        for (k, &word) in self.bits.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let i = k * 64 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                let (x, y) = (i % (self.w * 64), i / (self.w * 64));
+                v.push((self.x0 + x as i32, self.y0 + y as i32));
+            }
         }
-        let x0 = self.m.iter().map(|p| p.0).min().unwrap();
-        let x1 = self.m.iter().map(|p| p.0).max().unwrap();
-        let y0 = self.m.iter().map(|p| p.1).min().unwrap();
-        let y1 = self.m.iter().map(|p| p.1).max().unwrap();
+        v
+    }
 
-        let mut s = String::new();
-        for y in y0..=y1 {
-            for x in x0..=x1 {
-                s.push(if self.m.contains(&(x, y)) { '#' } else { '.' });
+    fn bbox(&self) -> Option<(Vec2, Vec2)> {
+        let rows: Vec<_> = self.bits.chunks(self.w).collect();
+        let y0 = rows.iter().position(|r| r.iter().any(|&w| w != 0))?;
+        let y1 = rows.iter().rposition(|r| r.iter().any(|&w| w != 0))?;
+        let mut col = vec![0u64; self.w];
+        for r in &rows[y0..=y1] {
+            col = or(&col, r);
+        }
+        let k0 = col.iter().position(|&w| w != 0)?;
+        let k1 = col.iter().rposition(|&w| w != 0)?;
+        let x0 = k0 * 64 + col[k0].trailing_zeros() as usize;
+        let x1 = k1 * 64 + 63 - col[k1].leading_zeros() as usize;
+        Some((
+            (self.x0 + x0 as i32, self.y0 + y0 as i32),
+            (self.x0 + x1 as i32, self.y0 + y1 as i32),
+        ))
+    }
+
+    // True if no tile within d of the grid border is set.
+    fn fits(&self, d: i32) -> bool {
+        let x1 = self.x0 + (self.w * 64) as i32 - 1;
+        let y1 = self.y0 + self.h as i32 - 1;
+        self.bbox().is_none_or(|(p0, p1)| {
+            p0.0 - self.x0 >= d && p0.1 - self.y0 >= d && x1 - p1.0 >= d && y1 - p1.1 >= d
+        })
+    }
+
+    // Layer whose tile p is set iff tile p + d is set in the given layer.
+    fn offset(&self, layer: &[u64], d: Vec2) -> Vec<u64> {
+        assert!(d.0.abs() < 64);
+        let mut out = self.empty();
+        let (w, dx) = (self.w, d.0);
+        let k = dx.unsigned_abs();
+        for y in 0..self.h {
+            let sy = y as i64 + d.1 as i64;
+            if !(0..self.h as i64).contains(&sy) {
+                continue;
+            }
+            let src = &layer[sy as usize * w..][..w];
+            let dst = &mut out[y * w..][..w];
+            for i in 0..w {
+                dst[i] = match dx.cmp(&0) {
+                    Ordering::Equal => src[i],
+                    Ordering::Greater => src[i] >> k | src.get(i + 1).map_or(0, |&n| n << (64 - k)),
+                    Ordering::Less => {
+                        src[i] << k | i.checked_sub(1).map_or(0, |j| src[j] >> (64 - k))
+                    }
+                };
             }
-            s.push('\n');
         }
-        s
+        out
     }
 }
 
+fn or(a: &[u64], b: &[u64]) -> Vec<u64> {
+    a.iter().zip(b).map(|(x, y)| x | y).collect()
+}
+
+fn and(a: &[u64], b: &[u64]) -> Vec<u64> {
+    a.iter().zip(b).map(|(x, y)| x & y).collect()
+}
+
+fn and_not(a: &[u64], b: &[u64]) -> Vec<u64> {
+    a.iter().zip(b).map(|(x, y)| x & !y).collect()
+}
+
+fn neg(d: Vec2) -> Vec2 {
+    (-d.0, -d.1)
+}
+
+fn area(p0: Vec2, p1: Vec2) -> usize {
+    ((p1.0 + 1 - p0.0) as usize) * ((p1.1 + 1 - p0.1) as usize)
+}
+
 fn poss(input: &str) -> impl Iterator<Item = Vec2> + '_ {
     input.lines().enumerate().flat_map(|(y, line)| {
         line.chars()
@@ -135,49 +389,178 @@ fn poss(input: &str) -> impl Iterator<Item = Vec2> + '_ {
 
 type Vec2 = (i32, i32);
 
-const NW: Vec2 = (-1, -1);
-const N: Vec2 = (0, -1);
-const NE: Vec2 = (1, -1);
-const W: Vec2 = (-1, 0);
-const E: Vec2 = (1, 0);
-const SW: Vec2 = (-1, 1);
-const S: Vec2 = (0, 1);
-const SE: Vec2 = (1, 1);
-
-static NBORS: &[Vec2] = &[NW, N, NE, W, E, SW, S, SE];
-
-static PROPOSALS: &[Proposal] = &[
-    Proposal {
-        check: &[N, NW, NE],
-        step: N,
-    },
-    Proposal {
-        check: &[S, SE, SW],
-        step: S,
-    },
-    Proposal {
-        check: &[W, SW, NW],
-        step: W,
-    },
-    Proposal {
-        check: &[E, SE, NE],
-        step: E,
-    },
+const COMPASS: &[(&str, Vec2)] = &[
+    ("NW", (-1, -1)),
+    ("N", (0, -1)),
+    ("NE", (1, -1)),
+    ("W", (-1, 0)),
+    ("E", (1, 0)),
+    ("SW", (-1, 1)),
+    ("S", (0, 1)),
+    ("SE", (1, 1)),
 ];
 
-#[derive(Debug, Copy, Clone)]
-struct Proposal<'a> {
-    check: &'a [Vec2],
+// Offsets further away than this are rejected.
+const MAX_OFFSET: i32 = 16;
+
+const DEFAULT_RULES: &str = "\
+neighbors NW N NE W E SW S SE
+propose N if N NW NE
+propose S if S SE SW
+propose W if W SW NW
+propose E if E SE NE
+rotate 1
+";
+
+// Elves with none of the neighbors occupied stay. The others step with
+// the first proposal whose checked tiles are all free, trying them in
+// order from the round number times the rotation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rules {
+    nbors: Vec<Vec2>,
+    proposals: Vec<Proposal>,
+    rotate: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Proposal {
+    check: Vec<Vec2>,
     step: Vec2,
 }
 
+impl Rules {
+    fn order(&self, n: usize) -> impl Iterator<Item = &Proposal> {
+        let l = self.proposals.len();
+        let r = if l == 0 { 0 } else { n % l * self.rotate };
+        (0..l).map(move |i| &self.proposals[(i + r) % l])
+    }
+
+    // All offsets that are looked at.
+    fn offsets(&self) -> impl Iterator<Item = Vec2> + '_ {
+        let mut v: Vec<_> = self
+            .nbors
+            .iter()
+            .chain(self.proposals.iter().flat_map(|p| &p.check))
+            .copied()
+            .collect();
+        v.sort();
+        v.dedup();
+        v.into_iter()
+    }
+
+    // Furthest distance an elf can move in a round.
+    fn reach(&self) -> i32 {
+        self.proposals
+            .iter()
+            .map(|p| p.step.0.abs().max(p.step.1.abs()))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Rules {
+        DEFAULT_RULES.parse().unwrap()
+    }
+}
+
+// One rule per line, blank lines and '#' comments are skipped:
+//   neighbors <offset>...             tiles that make an elf move
+//   propose <offset> if <offset>...   step, and the tiles to check
+//   rotate <k>                        advance the start by k each round
+// An offset is a compass name like NE, or dx,dy such as 1,-1.
+// Without a neighbors line, all checked tiles are neighbors.
+impl FromStr for Rules {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Rules> {
+        let mut nbors = None;
+        let mut proposals = Vec::new();
+        let mut rotate = 0;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let err = |msg: String| anyhow!("line {}: {}", i + 1, msg);
+            let mut words = line.split_whitespace();
+            let Some(kw) = words.next() else {
+                continue;
+            };
+            let offsets = |words: &mut dyn Iterator<Item = &str>| {
+                words
+                    .map(|w| parse_offset(w).map_err(err))
+                    .collect::<Result<Vec<_>>>()
+            };
+            match kw {
+                "neighbors" if nbors.is_some() => {
+                    return Err(err("duplicate neighbors".to_string()))
+                }
+                "neighbors" => nbors = Some(offsets(&mut words)?),
+                "propose" => {
+                    let step = words
+                        .next()
+                        .ok_or_else(|| err("missing step".to_string()))
+                        .and_then(|w| parse_offset(w).map_err(err))?;
+                    if words.next() != Some("if") {
+                        return Err(err("expected 'if' after the step".to_string()));
+                    }
+                    let check = offsets(&mut words)?;
+                    if !check.contains(&step) {
+                        return Err(err(format!("step {:?} is not checked", step)));
+                    }
+                    proposals.push(Proposal { check, step });
+                }
+                "rotate" => {
+                    rotate = match (words.next(), words.next()) {
+                        (Some(k), None) => k
+                            .parse()
+                            .map_err(|e| err(format!("invalid rotation {:?}: {}", k, e)))?,
+                        _ => return Err(err("expected one rotation count".to_string())),
+                    }
+                }
+                _ => return Err(err(format!("unknown rule {:?}", kw))),
+            }
+        }
+        if proposals.is_empty() {
+            bail!("no proposals");
+        }
+        let nbors = nbors.unwrap_or_else(|| {
+            let mut v: Vec<_> = proposals.iter().flat_map(|p| p.check.clone()).collect();
+            v.sort();
+            v.dedup();
+            v
+        });
+        Ok(Rules {
+            nbors,
+            proposals,
+            rotate,
+        })
+    }
+}
+
+fn parse_offset(w: &str) -> std::result::Result<Vec2, String> {
+    let d = match COMPASS.iter().find(|(name, _)| *name == w) {
+        Some(&(_, d)) => d,
+        None => {
+            let (x, y) = w
+                .split_once(',')
+                .ok_or_else(|| format!("invalid offset {:?}", w))?;
+            let num = |v: &str| v.parse().map_err(|_| format!("invalid offset {:?}", w));
+            (num(x)?, num(y)?)
+        }
+    };
+    if d.0.abs() > MAX_OFFSET || d.1.abs() > MAX_OFFSET {
+        return Err(format!("offset {:?} is too far", w));
+    }
+    Ok(d)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::util::Rng;
+    use std::collections::HashSet;
+    use std::time::Instant;
 
-    #[test]
-    fn day23_works() {
-        let sample = "\
+    const SAMPLE: &str = "\
 ....#..
 ..###.#
 #...#.#
@@ -186,7 +569,239 @@ mod test {
 ##.#.##
 .#..#..
 ";
-        assert_eq!(sim_step_area(sample, 10), 110);
-        assert_eq!(find_stop_round(sample), 20);
+
+    #[test]
+    fn day23_works() {
+        let rules = Rules::default();
+        assert_eq!(sim_step_area(SAMPLE, &rules, 10), 110);
+        assert_eq!(
+            find_stop_round(SAMPLE, &rules, &Render::default(), 100).unwrap(),
+            20
+        );
+    }
+
+    #[test]
+    fn render_works() {
+        let r: Rounds = "0, 3-4, */10".parse().unwrap();
+        let sel: Vec<_> = (0..25).filter(|&n| r.contains(n)).collect();
+        assert_eq!(sel, [0, 3, 4, 10, 20]);
+        assert!("*".parse::<Rounds>().unwrap().contains(7));
+        assert!("".parse::<Rounds>().unwrap().0.is_empty());
+        assert!("*/0".parse::<Rounds>().is_err());
+        assert!("3-x".parse::<Rounds>().is_err());
+
+        let dir = std::env::temp_dir().join(format!("aoc22-day23-{}", std::process::id()));
+        let render = Render {
+            rounds: "1,20-30".parse().unwrap(),
+            dir: Some(dir.clone()),
+        };
+        assert_eq!(
+            find_stop_round(SAMPLE, &Rules::default(), &render, 100).unwrap(),
+            20
+        );
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["round00001.txt", "round00020.txt"]);
+        let last = fs::read_to_string(dir.join("round00020.txt")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let (stats, grid) = last.split_once('\n').unwrap();
+        assert_eq!(
+            stats,
+            "22 elves, 0 moved, box (-3, -2)-(10, 9) 14x12, 146 empty"
+        );
+        assert_eq!(grid.lines().count(), 12);
+    }
+
+    // The elves in a hash set, as a reference for the bit grid.
+    struct HashSim {
+        m: HashSet<Vec2>,
+        n: usize,
+        rules: Rules,
+    }
+
+    impl HashSim {
+        fn from(input: &str, rules: &Rules) -> HashSim {
+            HashSim {
+                m: poss(input).collect(),
+                n: 0,
+                rules: rules.clone(),
+            }
+        }
+
+        fn step(&mut self) -> bool {
+            let mut goal = HashMap::new();
+            for (_, p) in self.nexts() {
+                if let Some(x) = p {
+                    *goal.entry(x).or_insert(0) += 1;
+                }
+            }
+            let mut moved = false;
+            let m2 = self
+                .nexts()
+                .map(|(e, p)| match p {
+                    Some(x) if goal[&x] == 1 => {
+                        moved = true;
+                        x
+                    }
+                    _ => e,
+                })
+                .collect();
+            self.m = m2;
+            self.n += 1;
+            moved
+        }
+
+        fn nexts(&self) -> impl Iterator<Item = (Vec2, Option<Vec2>)> + '_ {
+            let has = |e: Vec2, d: &Vec2| self.m.contains(&(e.0 + d.0, e.1 + d.1));
+            self.m.iter().map(move |&e| {
+                if !self.rules.nbors.iter().any(|d| has(e, d)) {
+                    return (e, None);
+                }
+                let p = self
+                    .rules
+                    .order(self.n)
+                    .find(|p| !p.check.iter().any(|d| has(e, d)))
+                    .map(|p| (e.0 + p.step.0, e.1 + p.step.1));
+                (e, p)
+            })
+        }
+    }
+
+    // Square of side n, with elves on about 1 / density of the tiles.
+    fn generate(n: usize, density: u64, seed: u64) -> String {
+        let mut rng = Rng::new(seed);
+        let mut s = String::new();
+        for _ in 0..n {
+            for _ in 0..n {
+                s.push(if rng.next_bits().is_multiple_of(density) {
+                    '#'
+                } else {
+                    '.'
+                });
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    const HEX_RULES: &str = "\
+# hex grid in axial coordinates
+propose 0,-1 if 0,-1 1,-1 -1,0
+propose 1,0 if 1,0 1,-1 0,1
+propose -1,1 if -1,1 -1,0 0,1
+propose 0,1 if 0,1 -1,1 1,0
+rotate 2
+";
+
+    const LEAP_RULES: &str = "\
+neighbors N S E W
+propose 0,-2 if N 0,-2  # jump north
+propose E if E NE SE
+propose 2,2 if 1,1 2,2
+";
+
+    #[test]
+    fn rules_work() {
+        let rules: Rules = DEFAULT_RULES.parse().unwrap();
+        assert_eq!(rules.nbors.len(), 8);
+        assert_eq!(rules.proposals[3].step, (1, 0));
+        assert_eq!(rules.order(5).next().unwrap().step, (0, 1));
+        assert_eq!(rules.reach(), 1);
+
+        let hex: Rules = HEX_RULES.parse().unwrap();
+        assert_eq!(hex.nbors.len(), 6);
+        assert_eq!(hex.order(1).next().unwrap().step, (-1, 1));
+        let leap: Rules = LEAP_RULES.parse().unwrap();
+        assert_eq!(leap.reach(), 2);
+        assert_eq!(leap.rotate, 0);
+        assert_eq!(leap.offsets().count(), 8);
+
+        for (spec, msg) in [
+            ("", "no proposals"),
+            ("propose N if S", "line 1: step (0, -1) is not checked"),
+            ("propose N N", "line 1: expected 'if' after the step"),
+            ("\npropose X if N", "line 2: invalid offset \"X\""),
+            (
+                "propose N if N\nrotate",
+                "line 2: expected one rotation count",
+            ),
+            ("propose 0,20 if 0,20", "line 1: offset \"0,20\" is too far"),
+            ("turn 1", "line 1: unknown rule \"turn\""),
+            (
+                "neighbors N\nneighbors S\npropose N if N",
+                "line 2: duplicate neighbors",
+            ),
+        ] {
+            assert_eq!(spec.parse::<Rules>().unwrap_err().to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn grid_matches_hash() {
+        let rule_sets: Vec<Rules> = [DEFAULT_RULES, HEX_RULES, LEAP_RULES]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        for rules in &rule_sets {
+            for (n, density, seed) in [(16, 2, 1), (24, 3, 2), (5, 1, 4)] {
+                let input = generate(n, density, seed);
+                let mut s = Sim::from(&input, rules);
+                let mut h = HashSim::from(&input, rules);
+                for _ in 0..80 {
+                    let (a, b) = (s.step() > 0, h.step());
+                    assert_eq!(a, b);
+                    assert_eq!(s.g.points().into_iter().collect::<HashSet<_>>(), h.m);
+                    if !a {
+                        break;
+                    }
+                }
+                assert_eq!(s.n, h.n);
+            }
+        }
+        let rules = Rules::default();
+        assert_eq!(
+            find_stop_round("", &rules, &Render::default(), 100).unwrap(),
+            1
+        );
+        assert_eq!(sim_step_area("", &rules, 10), 0);
+
+        // two elves chasing each other east never stop
+        let chase: Rules = "neighbors -2,0 W E 2,0\npropose E if E".parse().unwrap();
+        assert_eq!(
+            find_stop_round("##", &chase, &Render::default(), 50)
+                .unwrap_err()
+                .to_string(),
+            "elves still moving after 50 rounds"
+        );
+    }
+
+    // cargo test --release bench_stop_round -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_stop_round() {
+        let mut inputs: Vec<_> = [(75, 2), (150, 2), (300, 2)]
+            .iter()
+            .map(|&(n, d)| (format!("generated {}x{}", n, n), generate(n, d, n as u64)))
+            .collect();
+        inputs.insert(0, ("sample".to_string(), SAMPLE.to_string()));
+        for (name, input) in inputs {
+            let now = Instant::now();
+            let rules = Rules::default();
+            let mut s = Sim::from(&input, &rules);
+            while s.step() > 0 {}
+            let ts = now.elapsed();
+            let now = Instant::now();
+            let mut h = HashSim::from(&input, &rules);
+            while h.step() {}
+            let th = now.elapsed();
+            assert_eq!(s.n, h.n);
+            println!(
+                "{:16}  rounds {:5}  grid {:?}  hash {:?}",
+                name, s.n, ts, th
+            );
+        }
     }
 }
@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use pathfinding::prelude::astar;
 
 pub fn run(input: &str) -> Result<String> {
@@ -8,55 +8,127 @@ pub fn run(input: &str) -> Result<String> {
     Ok(format!("{} {}", p1, p2))
 }
 
-fn shortest(m: &Map) -> Result<Coord> {
-    let goal = (m.dx - 2, m.dy - 1);
-    let ref mut m = TimeMap::from(m);
-
-    let r = astar(
-        &(1, 0, 0),
-        |&n| m.nextv(n),
-        |&n| taxicab(flat(n), goal),
-        |&n| flat(n) == goal,
-    )
-    .map(|x| x.1);
-    r.ok_or_else(|| anyhow!("pathfind failed"))
+fn shortest(m: &Map) -> Result<usize> {
+    let r = itinerary(m, &[m.start(), m.goal()])?;
+    Ok(r.arrivals[1])
 }
 
-fn shortest_2(m: &Map) -> Result<Coord> {
-    let ref mut m = TimeMap::from(m);
-    let s = m.start;
-    let g = m.goal;
-
-    let r = astar(
-        &((s.0, s.1, 0), 0),
-        |&n| m.nextvs(n),
-        |&n| taxicab_est(n, s, g),
-        |&n| flat(n.0) == g && n.1 == 2,
-    )
-    .map(|x| x.1);
-    r.ok_or_else(|| anyhow!("pathfind failed"))
+fn shortest_2(m: &Map) -> Result<usize> {
+    let (s, g) = (m.start(), m.goal());
+    let r = itinerary(m, &[s, g, s, g])?;
+    if crate::verbose() {
+        r.show(m);
+    }
+    Ok(r.arrivals[3])
 }
 
-fn flat(p: Vec3) -> Vec2 {
-    (p.0, p.1)
+// Quickest trip through the waypoints in order: the arrival at each
+// waypoint and the position at each minute. A waypoint may be reachable
+// earlier than on the quickest trip, earliest has these minutes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Itinerary {
+    arrivals: Vec<usize>,
+    earliest: Vec<usize>,
+    path: Vec<Vec2>,
 }
 
-fn taxicab(p: Vec2, q: Vec2) -> Coord {
-    (p.0 - q.0).abs() + (p.1 - q.1).abs()
+impl Itinerary {
+    fn show(&self, m: &Map) {
+        let mut tm = TimeMap::from(m);
+        for (i, &t) in self.arrivals.iter().enumerate() {
+            println!(
+                "Waypoint {} {:?} reached at minute {}, earliest {}",
+                i, self.path[t], t, self.earliest[i]
+            );
+            let mut frame = tm.render(t);
+            *frame.at_mut(self.path[t]) = EXPEDITION;
+            print!("{}", frame.to_string_lines());
+        }
+    }
 }
 
-fn taxicab_est(n: (Vec3, State), start: Vec2, goal: Vec2) -> Coord {
-    let p = flat(n.0);
-    if n.1 == 0 {
-        taxicab(p, goal) + 2 * taxicab(goal, start)
-    } else if n.1 == 1 {
-        taxicab(p, start) + taxicab(start, goal)
+// The trip starts at the first waypoint at minute 0. The blizzards repeat
+// after TimeMap::period minutes, so the search state only keeps the time
+// modulo the period.
+fn itinerary(m: &Map, waypoints: &[Vec2]) -> Result<Itinerary> {
+    let Some(&first) = waypoints.first() else {
+        bail!("no waypoints");
+    };
+    for &w in waypoints {
+        if !m.inside(w) || m.at(w) == WALL {
+            bail!("waypoint {:?} is not in the basin", w);
+        }
+    }
+    let mut tm = TimeMap::from(m);
+    if tm.at(first, 0) != 0 {
+        bail!("waypoint {:?} is in a blizzard at minute 0", first);
+    }
+    let n = waypoints.len();
+    // distance from each waypoint to the last one along the itinerary
+    let mut tail = vec![0; n];
+    for i in (0..n - 1).rev() {
+        tail[i] = tail[i + 1] + taxicab(waypoints[i], waypoints[i + 1]);
+    }
+    // index of the last waypoint reached after stepping onto p
+    let reach = |p: Vec2, mut i: usize| {
+        while i + 1 < n && waypoints[i + 1] == p {
+            i += 1;
+        }
+        i
+    };
+
+    // quickest trip up to waypoint k
+    let mut trip = |k: usize| {
+        astar(
+            &(first, 0, reach(first, 0)),
+            |&(p, t, i)| {
+                let t = (t + 1) % tm.period;
+                tm.nexts(p, t)
+                    .map(|q| ((q, t, reach(q, i)), 1))
+                    .collect::<Vec<_>>()
+            },
+            |&(p, _, i)| match i < k {
+                true => taxicab(p, waypoints[i + 1]) + tail[i + 1] - tail[k],
+                false => 0,
+            },
+            |&(_, _, i)| i >= k,
+        )
+        .map(|(path, _)| path)
+        .ok_or_else(|| anyhow!("pathfind failed"))
+    };
+    let path = trip(n - 1)?;
+
+    let mut arrivals = vec![0; path[0].2 + 1];
+    for (t, w) in path.windows(2).enumerate() {
+        arrivals.resize(w[1].2 + 1, t + 1);
+    }
+    // the trip to the last waypoint need not be the quickest to the others
+    let mut earliest = arrivals.clone();
+    for (k, e) in earliest.iter_mut().enumerate().take(n - 1) {
+        if *e > 0 {
+            *e = trip(k)?.len() - 1;
+        }
+    }
+
+    Ok(Itinerary {
+        arrivals,
+        earliest,
+        path: path.into_iter().map(|(p, _, _)| p).collect(),
+    })
+}
+
+fn taxicab(p: Vec2, q: Vec2) -> usize {
+    ((p.0 - q.0).abs() + (p.1 - q.1).abs()) as usize
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
     } else {
-        taxicab(p, goal)
+        gcd(b, a % b)
     }
 }
 
-type State = u8;
 type Coord = i16;
 type Vec2 = (Coord, Coord);
 
@@ -88,33 +160,6 @@ impl Map {
         }
     }
 
-    fn blow(&self) -> Map {
-        let mut m = Map {
-            v: self
-                .v
-                .iter()
-                .map(|&c| if c < 0x10 { c } else { 0 })
-                .collect(),
-            ..*self
-        };
-        for x in 1..(self.dx - 1) {
-            for y in 1..(self.dy - 1) {
-                let p = (x, y);
-                let c = self.at(p);
-                if c < 0x10 {
-                    continue;
-                }
-                for (i, d) in DIRS.iter().enumerate() {
-                    let x = 1 << i;
-                    if (c & x) != 0 {
-                        *m.at_mut(self.windp(p, *d)) |= 0x10 | x;
-                    }
-                }
-            }
-        }
-        m
-    }
-
     fn at(&self, p: Vec2) -> u8 {
         self.v[self.pos(p)]
     }
@@ -124,8 +169,16 @@ impl Map {
         &mut self.v[p]
     }
 
-    fn windp(&self, p: Vec2, d: Vec2) -> Vec2 {
-        (cfix(p.0 + d.0, self.dx), cfix(p.1 + d.1, self.dy))
+    fn inside(&self, p: Vec2) -> bool {
+        (0..self.dx).contains(&p.0) && (0..self.dy).contains(&p.1)
+    }
+
+    fn start(&self) -> Vec2 {
+        (1, 0)
+    }
+
+    fn goal(&self) -> Vec2 {
+        (self.dx - 2, self.dy - 1)
     }
 
     fn pos(&self, p: Vec2) -> usize {
@@ -145,59 +198,132 @@ impl Map {
     }
 }
 
-type Vec3 = (Coord, Coord, Coord);
-
+// Blizzard frames for the minutes of one period, computed on demand.
+// Each frame holds one bitset per wind direction over the inner cells.
 struct TimeMap {
-    dy: Coord,
-    start: Vec2,
-    goal: Vec2,
-    tv: Vec<Map>,
+    period: usize,
+    w: usize,
+    h: usize,
+    base: Map,
+    frames: Vec<Frame>,
 }
 
+type Frame = [Bits; 4];
+
 impl TimeMap {
     fn from(m0: &Map) -> TimeMap {
+        let (w, h) = ((m0.dx - 2) as usize, (m0.dy - 2) as usize);
+        let mut f0: Frame = std::array::from_fn(|_| Bits::new(w * h));
+        for y in 0..h {
+            for x in 0..w {
+                let c = m0.at((x as Coord + 1, y as Coord + 1));
+                for (d, bits) in f0.iter_mut().enumerate() {
+                    if c >= 0x10 && c & (1 << d) != 0 {
+                        bits.set(x + y * w);
+                    }
+                }
+            }
+        }
         TimeMap {
-            dy: m0.dy,
-            start: (1, 0),
-            goal: (m0.dx - 2, m0.dy - 1),
-            tv: vec![m0.clone()],
+            period: w / gcd(w, h) * h,
+            w,
+            h,
+            base: m0.clone(),
+            frames: vec![f0],
         }
     }
 
-    fn at(&mut self, p: Vec3) -> u8 {
-        let p2 = (p.0, p.1);
-        let h = p.2 as usize;
-        while self.tv.len() <= h {
-            let m = &self.tv.last().unwrap();
-            self.tv.push(m.blow());
+    fn frame(&mut self, t: usize) -> &Frame {
+        let t = t % self.period;
+        while self.frames.len() <= t {
+            let f = self.blow(self.frames.len());
+            self.frames.push(f);
         }
-        self.tv[h].at(p2)
+        &self.frames[t]
     }
 
-    fn nextv(&mut self, n: Vec3) -> Vec<(Vec3, Coord)> {
-        self.nexts(n)
-            .map(|p| (p, 1))
-            .collect::<Vec<(Vec3, Coord)>>()
+    // Moves each blizzard of the first frame t steps along its wind.
+    fn blow(&self, t: usize) -> Frame {
+        let (w, h) = (self.w as i64, self.h as i64);
+        std::array::from_fn(|d| {
+            let mut bits = Bits::new(self.w * self.h);
+            let (dx, dy) = (DIRS[d].0 as i64, DIRS[d].1 as i64);
+            for i in self.frames[0][d].iter() {
+                let x = (i as i64 % w + dx * t as i64).rem_euclid(w);
+                let y = (i as i64 / w + dy * t as i64).rem_euclid(h);
+                bits.set((x + y * w) as usize);
+            }
+            bits
+        })
+    }
+
+    // Cell at minute t in the encoding of Map.
+    fn at(&mut self, p: Vec2, t: usize) -> u8 {
+        let c = self.base.at(p);
+        if c == WALL
+            || !(1..=self.w as Coord).contains(&p.0)
+            || !(1..=self.h as Coord).contains(&p.1)
+        {
+            return c;
+        }
+        let i = (p.0 - 1) as usize + (p.1 - 1) as usize * self.w;
+        let winds = (0..4)
+            .filter(|&d| self.frame(t)[d].get(i))
+            .fold(0, |c, d| c | (1 << d));
+        if winds == 0 {
+            0
+        } else {
+            0x10 | winds
+        }
     }
 
-    fn nextvs(&mut self, n: (Vec3, State)) -> Vec<((Vec3, State), Coord)> {
-        let p = n.0;
-        let mut s = n.1;
-        if s == 0 && flat(p) == self.goal {
-            s = 1;
-        } else if s == 1 && flat(p) == self.start {
-            s = 2;
+    fn render(&mut self, t: usize) -> Map {
+        let mut m = self.base.clone();
+        for y in 0..m.dy {
+            for x in 0..m.dx {
+                *m.at_mut((x, y)) = self.at((x, y), t);
+            }
         }
-        self.nexts(n.0)
-            .map(|p| ((p, s), 1))
-            .collect::<Vec<((Vec3, State), Coord)>>()
+        m
     }
 
-    fn nexts(&mut self, n: Vec3) -> impl Iterator<Item = Vec3> + '_ {
+    // Positions that are free at minute t, reachable from p at minute t - 1.
+    fn nexts(&mut self, p: Vec2, t: usize) -> impl Iterator<Item = Vec2> + '_ {
+        let dy = self.base.dy;
         XDIRS
             .iter()
-            .map(move |d| (n.0 + d.0, n.1 + d.1, n.2 + 1))
-            .filter(|p| (0..self.dy).contains(&p.1) && self.at(*p) == 0)
+            .map(move |d| (p.0 + d.0, p.1 + d.1))
+            .filter(move |q| (0..dy).contains(&q.1) && self.at(*q, t) == 0)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Bits(Vec<u64>);
+
+impl Bits {
+    fn new(n: usize) -> Bits {
+        Bits(vec![0; n.div_ceil(64)])
+    }
+
+    fn get(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn set(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(k, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                (word != 0).then(|| {
+                    let b = word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    k * 64 + b
+                })
+            })
+        })
     }
 }
 
@@ -205,6 +331,9 @@ const DIRC: &[char] = &['^', '>', 'v', '<'];
 const DIRS: &[Vec2] = &[(0, -1), (1, 0), (0, 1), (-1, 0)];
 const XDIRS: &[Vec2] = &[(0, -1), (1, 0), (0, 1), (-1, 0), (0, 0)];
 
+const WALL: u8 = 0x01;
+const EXPEDITION: u8 = 0x02;
+
 const ENC: &[(char, u8)] = &[
     ('#', 0x01),
     ('^', 0x11),
@@ -215,7 +344,11 @@ const ENC: &[(char, u8)] = &[
 
 fn wind_char(b: u8) -> char {
     if b < 0x10 {
-        return if b == 0 { '.' } else { '#' };
+        return match b {
+            0 => '.',
+            EXPEDITION => 'E',
+            _ => '#',
+        };
     }
     let (i, n) = (0..4).fold((0, 0), |(i, n), bit| {
         if b & (1 << bit) != 0 {
@@ -231,19 +364,10 @@ fn wind_char(b: u8) -> char {
     }
 }
 
-fn cfix(p: Coord, d: Coord) -> Coord {
-    if p == 0 {
-        d - 2
-    } else if p == d - 1 {
-        1
-    } else {
-        p
-    }
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::util::Rng;
 
     #[test]
     fn day24_wind() {
@@ -276,16 +400,109 @@ mod test {
 ";
 
         let m = Map::parse(start);
-        let sim = |n| {
-            let mut m = m.clone();
-            for _ in 0..n {
-                m = m.blow();
-            }
-            m.to_string_lines()
-        };
-        println!("{}", m.blow().to_string_lines());
+        let sim = |n| TimeMap::from(&m).render(n).to_string_lines();
+        println!("{}", sim(1));
         assert_eq!(sim(1), t1);
-        println!("{}", m.blow().blow().to_string_lines());
+        println!("{}", sim(2));
         assert_eq!(sim(2), t2);
+        assert_eq!(sim(5), start);
+        assert_eq!(sim(21), t1);
+    }
+
+    const SAMPLE: &str = "\
+#.######
+#>>.<^<#
+#.<..<<#
+#>v.><>#
+#<^v^^>#
+######.#
+";
+
+    #[test]
+    fn day24_works() {
+        let m = Map::parse(SAMPLE);
+        assert_eq!(shortest(&m).unwrap(), 18);
+        assert_eq!(shortest_2(&m).unwrap(), 54);
+        assert_eq!(TimeMap::from(&m).period, 12);
+    }
+
+    #[test]
+    fn itinerary_works() {
+        let m = Map::parse(SAMPLE);
+        let (s, g) = (m.start(), m.goal());
+        let r = itinerary(&m, &[s, g, s, g]).unwrap();
+        assert_eq!(r.arrivals, [0, 18, 41, 54]);
+        assert_eq!(r.path.len(), 55);
+        for &(i, t) in &[(0, 0), (1, 18), (2, 41), (3, 54)] {
+            assert_eq!(r.path[t], [s, g][i % 2]);
+        }
+        let mut tm = TimeMap::from(&m);
+        for (t, w) in r.path.windows(2).enumerate() {
+            assert!(taxicab(w[0], w[1]) <= 1);
+            assert_eq!(tm.at(w[1], t + 1), 0);
+        }
+
+        assert_eq!(itinerary(&m, &[s]).unwrap().arrivals, [0]);
+        assert_eq!(itinerary(&m, &[s, s, g]).unwrap().arrivals, [0, 0, 18]);
+        assert!(itinerary(&m, &[s, (3, 3), g]).unwrap().arrivals[1] > 0);
+        assert!(itinerary(&m, &[]).is_err());
+        assert!(itinerary(&m, &[s, (0, 1)]).is_err());
+        assert!(itinerary(&m, &[s, (3, 9)]).is_err());
+        assert_eq!(r.earliest, r.arrivals);
+    }
+
+    #[test]
+    fn earliest_arrivals() {
+        // reaching (3, 3) at minute 9 leaves no quick way on to the goal
+        let m = Map::parse(
+            "\
+#.####
+#..vv#
+#....#
+#><>.#
+####.#
+",
+        );
+        let (s, g) = (m.start(), m.goal());
+        let r = itinerary(&m, &[s, (3, 3), g]).unwrap();
+        assert_eq!(r.arrivals, [0, 13, 17]);
+        assert_eq!(r.earliest, [0, 9, 17]);
+        assert_eq!(itinerary(&m, &[s, (3, 3)]).unwrap().arrivals, [0, 9]);
+    }
+
+    // Basin of w x h inner cells with pseudo random blizzards.
+    fn generate(w: usize, h: usize, seed: u64) -> String {
+        let mut rng = Rng::new(seed);
+        let mut s = format!("#.{}\n", "#".repeat(w));
+        for _ in 0..h {
+            s.push('#');
+            for _ in 0..w {
+                s.push(match rng.next_bits() >> 27 {
+                    0 => '^',
+                    1 => '>',
+                    2 => 'v',
+                    3 => '<',
+                    _ => '.',
+                });
+            }
+            s.push_str("#\n");
+        }
+        s + &format!("{}.#\n", "#".repeat(w))
+    }
+
+    #[test]
+    fn large_basin() {
+        let m = Map::parse(&generate(150, 40, 24));
+        let mut tm = TimeMap::from(&m);
+        assert_eq!(tm.period, 600);
+        assert_eq!(tm.render(600), m);
+        assert_eq!(tm.frames.len(), 1);
+        let r = itinerary(&m, &[m.start(), m.goal(), m.start()]).unwrap();
+        assert!(r.arrivals[1] >= 150 + 40);
+        for (t, w) in r.path.windows(2).enumerate() {
+            assert!(taxicab(w[0], w[1]) <= 1);
+            assert_eq!(tm.at(w[1], t + 1), 0);
+        }
+        assert!(tm.frames.len() <= tm.period);
     }
 }
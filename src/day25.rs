@@ -1,45 +1,44 @@
+use crate::balanced::Snafu;
 use anyhow::Result;
 
 pub fn run(input: &str) -> Result<String> {
-    let p1 = sum(input);
+    let p1 = sum(input)?;
     let p2 = "";
     Ok(format!("{} {}", p1, p2))
 }
 
-fn sum(input: &str) -> String {
-    to_snafu(input.lines().map(from_snafu).sum())
+fn sum(input: &str) -> Result<Snafu> {
+    input.lines().map(|s| s.trim().parse::<Snafu>()).sum()
 }
 
-type Num = i64;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-fn from_snafu(s: &str) -> Num {
-    s.chars()
-        .filter_map(from_snafu_char)
-        .fold(0, |acc, n| 5 * acc + n)
-}
+    #[test]
+    fn day25_works() {
+        assert_eq!(sum(SAMPLE).unwrap().to_string(), "2=-1=0");
+        assert!(sum("1=\n1x\n").is_err());
 
-fn to_snafu(i: Num) -> String {
-    let mut v = vec![];
-    let mut i = i;
-    while i > 0 {
-        let m = i % 5;
-        i /= 5;
-        let d = SNAFU_DIGIT[m as usize];
-        v.push(d.0);
-        i += d.1;
+        // sums past 64 bits stay exact
+        let big = "2".repeat(30);
+        let s = sum(&format!("{}\n{}\n", big, big)).unwrap();
+        assert_eq!(s.to_string(), format!("1{}-", "0".repeat(29)));
     }
-    v.iter().rev().collect()
-}
 
-fn from_snafu_char(c: char) -> Option<Num> {
-    match c {
-        '2' => Some(2),
-        '1' => Some(1),
-        '0' => Some(0),
-        '-' => Some(-1),
-        '=' => Some(-2),
-        _ => None,
-    }
+    const SAMPLE: &str = "\
+1=-0-2
+12111
+2=0=
+21
+2=01
+111
+20012
+112
+1=-1=
+1-12
+12
+1=
+122
+";
 }
-
-const SNAFU_DIGIT: &[(char, Num)] = &[('0', 0), ('1', 0), ('2', 0), ('=', 1), ('-', 1)];
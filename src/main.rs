@@ -62,6 +62,8 @@ fn day_funcs() -> Vec<DayFunc> {
     ]
 }
 
+mod balanced;
+mod param;
 mod quadmap;
 
 mod util;
@@ -75,6 +77,10 @@ struct Cli {
     #[arg(short, long)]
     all: bool,
 
+    /// Override a puzzle constant, e.g. day15.row=10
+    #[arg(short, long = "param", value_name = "NAME=VALUE", value_parser = param::parse_arg)]
+    params: Vec<(String, String)>,
+
     days: Vec<usize>,
 }
 
@@ -109,6 +115,10 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
+    for name in param::unused(&Cli::global().params) {
+        eprintln!("parameter {} was not used", name);
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,79 @@
+use crate::CLI_INSTANCE;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Puzzle constant that can be overridden on the command line
+/// with `--param <name>=<value>`, e.g. `--param day15.row=10`.
+pub struct Param<T> {
+    name: &'static str,
+    default: T,
+}
+
+static USED: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+impl<T> Param<T>
+where
+    T: FromStr + Clone,
+    T::Err: fmt::Display,
+{
+    pub const fn new(name: &'static str, default: T) -> Param<T> {
+        Param { name, default }
+    }
+
+    pub fn get(&self) -> Result<T> {
+        let mut used = USED.lock().unwrap();
+        if !used.contains(&self.name) {
+            used.push(self.name);
+        }
+        drop(used);
+
+        let value = CLI_INSTANCE
+            .get()
+            .and_then(|cli| cli.params.iter().rev().find(|(n, _)| n == self.name));
+        match value {
+            Some((_, v)) => v
+                .parse()
+                .map_err(|e| anyhow!("invalid value {:?} for {}: {}", v, self.name, e)),
+            None => Ok(self.default.clone()),
+        }
+    }
+}
+
+pub fn parse_arg(s: &str) -> std::result::Result<(String, String), String> {
+    let (n, v) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <name>=<value>, got {:?}", s))?;
+    Ok((n.trim().to_string(), v.trim().to_string()))
+}
+
+/// Returns the names of parameters given on the command line
+/// that no puzzle has asked for.
+pub fn unused(params: &[(String, String)]) -> Vec<&str> {
+    let used = USED.lock().unwrap();
+    params
+        .iter()
+        .map(|(n, _)| n.as_str())
+        .filter(|n| !used.contains(n))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn param_works() {
+        assert_eq!(
+            parse_arg("day15.row = 10"),
+            Ok(("day15.row".to_string(), "10".to_string()))
+        );
+        assert!(parse_arg("day15.row").is_err());
+
+        const P: Param<i32> = Param::new("test.p", 7);
+        assert_eq!(P.get().ok(), Some(7));
+        let params = [("test.p".to_string(), String::new())];
+        assert!(unused(&params).is_empty());
+    }
+}
@@ -85,3 +85,23 @@ impl InputSource {
         }
     }
 }
+
+// Seeded pseudo random numbers for generated test inputs.
+#[cfg(test)]
+pub struct Rng(u64);
+
+#[cfg(test)]
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    // The next 31 random bits.
+    pub fn next_bits(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}